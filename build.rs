@@ -0,0 +1,26 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=include");
+    #[cfg(feature = "bindgen")]
+    generate_abi_bindings();
+}
+
+/// Parse the vendored module headers so the tests can compare layouts.
+#[cfg(feature = "bindgen")]
+fn generate_abi_bindings() {
+    for header in ["dune.h", "cpu-x86.h", "vmpl.h"] {
+        if !std::path::Path::new("include/upstream").join(header).exists() {
+            panic!("include/upstream/{} is missing; run scripts/vendor-headers.sh", header);
+        }
+    }
+    let out = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindgen::Builder::default()
+        .header("include/abi.h")
+        .clang_arg("-Iinclude")
+        .allowlist_type("dune_.*|vmpl_.*|vmsa_seg|vcpu_config|get_pages_t|Tss")
+        .layout_tests(false)
+        .generate()
+        .expect("failed to generate bindings for include/abi.h")
+        .write_to_file(out.join("abi.rs"))
+        .expect("failed to write ABI bindings");
+}
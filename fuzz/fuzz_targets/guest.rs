@@ -0,0 +1,45 @@
+//! Feeds libFuzzer inputs to a guest restored from a snapshot.
+//!
+//! `DUNE_FUZZ_SNAPSHOT` names a file written by `Snapshot::save` just before
+//! the guest reads its input, and `DUNE_FUZZ_INPUT` the guest buffer the
+//! input goes to, as `addr,max_len`, e.g. `0x7f0000000000,4096`. Run with
+//! `cargo fuzz run guest` on a host with the Dune or VMPL module loaded.
+#![no_main]
+
+use std::cell::RefCell;
+
+use dune_sys::{open_backend, FuzzHarness, FuzzInput, HostMemory, Snapshot};
+use libfuzzer_sys::fuzz_target;
+
+fn env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name))
+}
+
+fn input() -> FuzzInput {
+    let spec = env("DUNE_FUZZ_INPUT");
+    let parsed = spec.split_once(',').and_then(|(addr, len)| {
+        let addr = u64::from_str_radix(addr.trim().trim_start_matches("0x"), 16).ok()?;
+        Some(FuzzInput {
+            addr,
+            max_len: len.trim().parse().ok()?,
+        })
+    });
+    parsed.unwrap_or_else(|| panic!("DUNE_FUZZ_INPUT must be addr,max_len, not {:?}", spec))
+}
+
+fn harness() -> FuzzHarness<HostMemory> {
+    let snapshot = Snapshot::load(env("DUNE_FUZZ_SNAPSHOT")).expect("failed to load snapshot");
+    snapshot.map_host_pages().expect("failed to map snapshot pages");
+    let mut memory = HostMemory::new();
+    snapshot.restore(&mut memory).expect("failed to restore snapshot");
+    let backend = open_backend().expect("failed to open the Dune or VMPL device");
+    FuzzHarness::new(backend, memory, snapshot, input())
+}
+
+thread_local! {
+    static HARNESS: RefCell<FuzzHarness<HostMemory>> = RefCell::new(harness());
+}
+
+fuzz_target!(|data: &[u8]| {
+    HARNESS.with(|harness| harness.borrow_mut().check(data));
+});
@@ -0,0 +1,4 @@
+/* Everything build.rs hands to bindgen: the module headers, unmodified. */
+#include "upstream/dune.h"
+#include "upstream/cpu-x86.h"
+#include "upstream/vmpl.h"
@@ -0,0 +1,56 @@
+#!/bin/sh
+# Copy the kernel module headers listed in include/UPSTREAM into
+# include/upstream/ verbatim, pinning each entry to the commit it came from.
+#
+# usage: scripts/vendor-headers.sh           fetch and pin every entry
+#        scripts/vendor-headers.sh --check   fail if a vendored file differs
+#                                            from its pinned commit
+set -eu
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+manifest="$root/include/UPSTREAM"
+dest="$root/include/upstream"
+check=0
+[ "${1:-}" = "--check" ] && check=1
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+fetch() { # repo rev path out -> prints the resolved commit
+    rm -rf "$work/repo"
+    git init -q "$work/repo"
+    git -C "$work/repo" fetch -q --depth 1 "$1" "$2"
+    git -C "$work/repo" show "FETCH_HEAD:$3" > "$4"
+    git -C "$work/repo" rev-parse FETCH_HEAD
+}
+
+mkdir -p "$dest"
+: > "$work/manifest"
+status=0
+while IFS= read -r line; do
+    case "$line" in
+    ''|'#'*) echo "$line" >> "$work/manifest"; continue ;;
+    esac
+    set -- $line
+    file=$1 repo=$2 rev=$3 path=$4
+    commit=$(fetch "$repo" "$rev" "$path" "$work/$file")
+    if [ $check -eq 1 ]; then
+        if ! cmp -s "$work/$file" "$dest/$file"; then
+            echo "include/upstream/$file differs from $repo $commit:$path" >&2
+            status=1
+        fi
+    else
+        cp "$work/$file" "$dest/$file"
+        echo "vendored $file from $repo $commit:$path"
+    fi
+    echo "$file $repo $commit $path" >> "$work/manifest"
+done < "$manifest"
+
+[ $check -eq 0 ] && cp "$work/manifest" "$manifest"
+for header in dune.h cpu-x86.h vmpl.h; do
+    if [ ! -f "$dest/$header" ]; then
+        echo "include/upstream/$header is missing; add it to include/UPSTREAM" >&2
+        status=1
+    fi
+done
+exit $status
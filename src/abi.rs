@@ -0,0 +1,135 @@
+//! Layout checks for the structs shared with the Dune and VMPL modules and
+//! with libdune (`Tss`).
+//!
+//! Each shared struct records its size, alignment and field offsets with
+//! `abi_layout!` next to its definition. With the `bindgen` feature, build.rs
+//! parses the headers vendored verbatim in `include/upstream` (see
+//! `include/UPSTREAM` for their origin) and the tests compare the two field
+//! by field.
+
+use crate::debug::{DuneTrapConfig, DuneTrapRegs};
+use crate::dune::{DuneConfig, DuneLayout};
+use crate::tss::Tss;
+use crate::vmpl::{GetPages, VcpuConfig, VmplArgs, VmplLayout, VmplSeimi, VmsaSeg};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FieldOffset {
+    pub name: &'static str,
+    pub offset: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    /// Name of the matching struct in the C headers.
+    pub c_name: &'static str,
+    pub size: usize,
+    pub align: usize,
+    pub fields: &'static [FieldOffset],
+}
+
+impl StructLayout {
+    pub fn offset(&self, field: &str) -> Option<usize> {
+        self.fields.iter().find(|f| f.name == field).map(|f| f.offset)
+    }
+}
+
+/// A struct whose layout must match a C struct of the kernel module.
+pub trait AbiLayout {
+    const LAYOUT: StructLayout;
+    /// The same struct as bindgen sees it in the vendored headers.
+    #[cfg(feature = "bindgen")]
+    const C_LAYOUT: StructLayout;
+}
+
+/// `abi_layout!(Type => c_struct { field, rust_field as c_field, ... })`
+///
+/// Must be invoked where the fields are visible. `as` names the C field when
+/// bindgen renames it, e.g. a C field called `priv`.
+macro_rules! abi_layout {
+    ($ty:ty => $c:ident { $($field:ident $(as $c_field:ident)?),* $(,)? }) => {
+        impl $crate::abi::AbiLayout for $ty {
+            const LAYOUT: $crate::abi::StructLayout = $crate::abi::StructLayout {
+                c_name: stringify!($c),
+                size: ::std::mem::size_of::<$ty>(),
+                align: ::std::mem::align_of::<$ty>(),
+                fields: &[$($crate::abi::FieldOffset {
+                    name: stringify!($field),
+                    offset: ::std::mem::offset_of!($ty, $field),
+                }),*],
+            };
+
+            #[cfg(feature = "bindgen")]
+            const C_LAYOUT: $crate::abi::StructLayout = $crate::abi::StructLayout {
+                c_name: stringify!($c),
+                size: ::std::mem::size_of::<$crate::abi::c::$c>(),
+                align: ::std::mem::align_of::<$crate::abi::c::$c>(),
+                fields: &[$($crate::abi::FieldOffset {
+                    name: stringify!($field),
+                    offset: $crate::abi::c_offset!($c, $field $(, $c_field)?),
+                }),*],
+            };
+        }
+    };
+}
+
+#[cfg(feature = "bindgen")]
+macro_rules! c_offset {
+    ($c:ident, $field:ident) => {
+        ::std::mem::offset_of!($crate::abi::c::$c, $field)
+    };
+    ($c:ident, $field:ident, $c_field:ident) => {
+        ::std::mem::offset_of!($crate::abi::c::$c, $c_field)
+    };
+}
+
+pub(crate) use abi_layout;
+#[cfg(feature = "bindgen")]
+pub(crate) use c_offset;
+
+/// Bindings generated by build.rs from `include/abi.h`.
+#[cfg(feature = "bindgen")]
+#[allow(non_camel_case_types, non_snake_case, non_upper_case_globals, dead_code)]
+pub(crate) mod c {
+    include!(concat!(env!("OUT_DIR"), "/abi.rs"));
+}
+
+/// Every struct passed to or from the kernel modules.
+pub static ABI_LAYOUTS: &[StructLayout] = &[
+    DuneConfig::LAYOUT,
+    DuneLayout::LAYOUT,
+    DuneTrapRegs::LAYOUT,
+    DuneTrapConfig::LAYOUT,
+    VmplLayout::LAYOUT,
+    VmplArgs::LAYOUT,
+    GetPages::LAYOUT,
+    VmplSeimi::LAYOUT,
+    VmsaSeg::LAYOUT,
+    VcpuConfig::LAYOUT,
+    Tss::LAYOUT,
+];
+
+#[cfg(all(test, feature = "bindgen"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_match_bindgen() {
+        let c_layouts = [
+            DuneConfig::C_LAYOUT,
+            DuneLayout::C_LAYOUT,
+            DuneTrapRegs::C_LAYOUT,
+            DuneTrapConfig::C_LAYOUT,
+            VmplLayout::C_LAYOUT,
+            VmplArgs::C_LAYOUT,
+            GetPages::C_LAYOUT,
+            VmplSeimi::C_LAYOUT,
+            VmsaSeg::C_LAYOUT,
+            VcpuConfig::C_LAYOUT,
+            Tss::C_LAYOUT,
+        ];
+        assert_eq!(ABI_LAYOUTS.len(), c_layouts.len());
+        for (rust, c) in ABI_LAYOUTS.iter().zip(&c_layouts) {
+            assert_eq!(rust, c);
+        }
+    }
+}
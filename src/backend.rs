@@ -0,0 +1,438 @@
+use std::ptr;
+use std::sync::Mutex;
+
+use libc::c_int;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::dev::*;
+use crate::dune::{DuneConfig, DuneLayout, DUNE_RET_EXIT};
+use crate::instrument::{exit_event, ioctl_event, trace_event};
+use crate::ioctl_error::{
+    EnterError, GetPagesError, LayoutError, PageVmplError, VmplLayoutError, VmplRunError,
+};
+use crate::probe::{detect_backend, BackendKind};
+use crate::vmpl::{GetPages, VmplArgs, VmplLayout};
+use crate::{Error, Result};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/* permission bits, following the RMPADJUST VMPL permission mask */
+pub const PAGE_PERM_READ: u32 = 1 << 0;
+pub const PAGE_PERM_WRITE: u32 = 1 << 1;
+pub const PAGE_PERM_EXEC_USER: u32 = 1 << 2;
+pub const PAGE_PERM_EXEC_SUPER: u32 = 1 << 3;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Layout {
+    Dune(DuneLayout),
+    Vmpl(VmplLayout),
+}
+
+impl Layout {
+    /// Start of the region the backend hands out guest mappings from.
+    pub fn mmap_base(&self) -> VirtAddr {
+        match self {
+            Layout::Dune(layout) => layout.base_map(),
+            Layout::Vmpl(layout) => layout.mmap_base(),
+        }
+    }
+
+    /// Highest guest physical address the backend exposes.
+    pub fn phys_limit(&self) -> PhysAddr {
+        match self {
+            Layout::Dune(layout) => layout.phys_limit(),
+            Layout::Vmpl(layout) => layout.phys_end(),
+        }
+    }
+}
+
+/// Operations shared by the Dune and VMPL devices.
+pub trait Backend: Send + Sync {
+    fn kind(&self) -> BackendKind;
+    fn layout(&self) -> Result<Layout>;
+    fn enter(&self, config: &mut DuneConfig) -> Result<()>;
+    fn set_page_perm(&self, va: VirtAddr, nr_pages: u32, perm: u32) -> Result<()>;
+    fn alloc_pages(&self, nr_pages: u64) -> Result<GetPages>;
+}
+
+pub(crate) fn prot_from_perm(perm: u32) -> c_int {
+    let mut prot = libc::PROT_NONE;
+    if perm & PAGE_PERM_READ != 0 {
+        prot |= libc::PROT_READ;
+    }
+    if perm & PAGE_PERM_WRITE != 0 {
+        prot |= libc::PROT_WRITE;
+    }
+    if perm & (PAGE_PERM_EXEC_USER | PAGE_PERM_EXEC_SUPER) != 0 {
+        prot |= libc::PROT_EXEC;
+    }
+    prot
+}
+
+fn mmap_pages(nr_pages: u64) -> Result<u64> {
+    let len = nr_pages
+        .checked_mul(PAGE_SIZE)
+        .ok_or_else(|| Error::InvalidInput(format!("Too many pages: {}", nr_pages)))?;
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::last_syscall("mmap"));
+    }
+    Ok(addr as u64)
+}
+
+/// Backend for VT-x hosts, driven through `/dev/dune`.
+#[derive(Debug)]
+pub struct DuneDevice {
+    device: BaseDevice,
+}
+
+impl DuneDevice {
+    pub fn open() -> Result<Self> {
+        Self::open_path(DUNE_DEVICE_PATH)
+    }
+
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut device = BaseDevice::new();
+        device.open(path)?;
+        Ok(Self { device })
+    }
+
+    pub fn device(&self) -> &BaseDevice {
+        &self.device
+    }
+
+    pub fn dune_layout(&self) -> std::result::Result<DuneLayout, LayoutError> {
+        let mut layout = DuneLayout::default();
+        let result = unsafe { dune_get_layout(self.device.fd(), &mut layout) };
+        ioctl_event!("DUNE_GET_LAYOUT", self.device.fd(), result, ?layout);
+        result?;
+        Ok(layout)
+    }
+
+    /// `DUNE_ENTER`, with the failure classified.
+    pub fn run(&self, config: &mut DuneConfig) -> std::result::Result<(), EnterError> {
+        trace_event!(TRACE, rip = config.rip(), rsp = config.rsp(), "entering guest");
+        let result = unsafe { dune_enter(self.device.fd(), config) };
+        ioctl_event!("DUNE_ENTER", self.device.fd(), result);
+        result?;
+        exit_event(config);
+        Ok(())
+    }
+}
+
+impl Backend for DuneDevice {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Dune
+    }
+
+    fn layout(&self) -> Result<Layout> {
+        Ok(Layout::Dune(self.dune_layout()?))
+    }
+
+    fn enter(&self, config: &mut DuneConfig) -> Result<()> {
+        Ok(self.run(config)?)
+    }
+
+    /// Dune mirrors the host page tables into the EPT, so this is a plain `mprotect`.
+    fn set_page_perm(&self, va: VirtAddr, nr_pages: u32, perm: u32) -> Result<()> {
+        let len = nr_pages as u64 * PAGE_SIZE;
+        let ret = unsafe { libc::mprotect(va.as_u64() as *mut _, len as usize, prot_from_perm(perm)) };
+        if ret < 0 {
+            return Err(Error::last_memory(va.as_u64()));
+        }
+        Ok(())
+    }
+
+    fn alloc_pages(&self, nr_pages: u64) -> Result<GetPages> {
+        let layout = self.dune_layout()?;
+        let mapping = mmap_pages(nr_pages)?;
+        let Some(phys) = layout.va_to_pa(VirtAddr::new(mapping)) else {
+            unsafe { libc::munmap(mapping as *mut _, (nr_pages * PAGE_SIZE) as usize) };
+            return Err(Error::InvalidAddress);
+        };
+        let mut pages = GetPages::new();
+        pages.set_num_pages(nr_pages).set_mapping(mapping).set_phys(phys.as_u64());
+        Ok(pages)
+    }
+}
+
+impl Drop for DuneDevice {
+    fn drop(&mut self) {
+        let _ = self.device.close();
+    }
+}
+
+/// Backend for SEV-SNP hosts, driven through the `vmpl_*` ioctls.
+#[derive(Debug)]
+pub struct VmplDevice {
+    device: BaseDevice,
+}
+
+impl VmplDevice {
+    pub fn open() -> Result<Self> {
+        Self::open_path(VMPL_DEVICE_PATH)
+    }
+
+    pub fn open_path(path: &str) -> Result<Self> {
+        let mut device = BaseDevice::new();
+        device.open(path)?;
+        Ok(Self { device })
+    }
+
+    pub fn device(&self) -> &BaseDevice {
+        &self.device
+    }
+
+    pub fn vmpl_layout(&self) -> std::result::Result<VmplLayout, VmplLayoutError> {
+        let mut layout = VmplLayout::new();
+        let result = unsafe { vmpl_get_layout(self.device.fd(), &mut layout) };
+        ioctl_event!("VMPL_GET_LAYOUT", self.device.fd(), result, ?layout);
+        result?;
+        Ok(layout)
+    }
+
+    /// `VMPL_RUN`, with the failure classified.
+    pub fn run(&self, config: &mut DuneConfig) -> std::result::Result<(), VmplRunError> {
+        trace_event!(TRACE, rip = config.rip(), rsp = config.rsp(), "entering guest");
+        let result = unsafe { vmpl_vmpl_run(self.device.fd(), config) };
+        ioctl_event!("VMPL_RUN", self.device.fd(), result);
+        result?;
+        exit_event(config);
+        Ok(())
+    }
+
+    pub fn set_page_vmpl(&self, args: &mut VmplArgs) -> std::result::Result<(), PageVmplError> {
+        let result = unsafe { vmpl_set_page_vmpl(self.device.fd(), args) };
+        ioctl_event!(
+            "VMPL_SET_PAGE_VMPL",
+            self.device.fd(),
+            result,
+            gva = args.gva(),
+            nr_pages = args.nr_pages(),
+            attrs = args.attrs()
+        );
+        result?;
+        Ok(())
+    }
+
+    pub fn get_pages(&self, pages: &mut GetPages) -> std::result::Result<(), GetPagesError> {
+        let result = unsafe { vmpl_get_pages(self.device.fd(), pages) };
+        ioctl_event!(
+            "VMPL_GET_PAGES",
+            self.device.fd(),
+            result,
+            num_pages = pages.num_pages(),
+            phys = pages.phys()
+        );
+        result?;
+        Ok(())
+    }
+}
+
+impl Backend for VmplDevice {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Vmpl
+    }
+
+    fn layout(&self) -> Result<Layout> {
+        Ok(Layout::Vmpl(self.vmpl_layout()?))
+    }
+
+    fn enter(&self, config: &mut DuneConfig) -> Result<()> {
+        Ok(self.run(config)?)
+    }
+
+    fn set_page_perm(&self, va: VirtAddr, nr_pages: u32, perm: u32) -> Result<()> {
+        let mut args = VmplArgs::new(va.as_u64(), PAGE_SIZE as u32, perm, nr_pages);
+        Ok(self.set_page_vmpl(&mut args)?)
+    }
+
+    fn alloc_pages(&self, nr_pages: u64) -> Result<GetPages> {
+        let mut pages = GetPages::new();
+        pages.set_num_pages(nr_pages);
+        self.get_pages(&mut pages)?;
+        Ok(pages)
+    }
+}
+
+impl Drop for VmplDevice {
+    fn drop(&mut self) {
+        let _ = self.device.close();
+    }
+}
+
+type MockEnterFn = Box<dyn FnMut(&mut DuneConfig) + Send>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MockPagePerm {
+    pub va: u64,
+    pub nr_pages: u32,
+    pub perm: u32,
+}
+
+/// In-process backend for tests: pages come from anonymous memory and
+/// `enter` runs a caller-supplied closure instead of the guest.
+pub struct MockBackend {
+    layout: Layout,
+    on_enter: Mutex<MockEnterFn>,
+    perms: Mutex<Vec<MockPagePerm>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::with_layout(Layout::Dune(DuneLayout::default()))
+    }
+
+    pub fn with_layout(layout: Layout) -> Self {
+        Self {
+            layout,
+            on_enter: Mutex::new(Box::new(|config: &mut DuneConfig| {
+                config.set_ret(DUNE_RET_EXIT);
+            })),
+            perms: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn on_enter<F>(&self, f: F) -> &Self
+    where
+        F: FnMut(&mut DuneConfig) + Send + 'static,
+    {
+        *self.on_enter.lock().unwrap() = Box::new(f);
+        self
+    }
+
+    pub fn page_perms(&self) -> Vec<MockPagePerm> {
+        self.perms.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for MockBackend {
+    fn kind(&self) -> BackendKind {
+        match self.layout {
+            Layout::Dune(_) => BackendKind::Dune,
+            Layout::Vmpl(_) => BackendKind::Vmpl,
+        }
+    }
+
+    fn layout(&self) -> Result<Layout> {
+        Ok(self.layout)
+    }
+
+    fn enter(&self, config: &mut DuneConfig) -> Result<()> {
+        (self.on_enter.lock().unwrap())(config);
+        Ok(())
+    }
+
+    fn set_page_perm(&self, va: VirtAddr, nr_pages: u32, perm: u32) -> Result<()> {
+        self.perms.lock().unwrap().push(MockPagePerm {
+            va: va.as_u64(),
+            nr_pages,
+            perm,
+        });
+        Ok(())
+    }
+
+    fn alloc_pages(&self, nr_pages: u64) -> Result<GetPages> {
+        let mapping = mmap_pages(nr_pages)?;
+        let mut pages = GetPages::new();
+        pages.set_num_pages(nr_pages).set_mapping(mapping).set_phys(mapping);
+        Ok(pages)
+    }
+}
+
+/// Open whichever backend this host supports behind a `Backend` object.
+pub fn open_backend() -> Result<Box<dyn Backend>> {
+    match detect_backend()? {
+        BackendKind::Dune => Ok(Box::new(DuneDevice::open()?)),
+        BackendKind::Vmpl => Ok(Box::new(VmplDevice::open()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno;
+    use crate::dune::{GPA_MAP_SIZE, GPA_STACK_SIZE};
+
+    fn dune_layout() -> DuneLayout {
+        DuneLayout::new(
+            PhysAddr::new(1 << 40),
+            VirtAddr::new(0x7f00_0000_0000),
+            VirtAddr::new(0x7fff_0000_0000),
+        )
+    }
+
+    #[test]
+    fn dune_address_translation() {
+        let layout = dune_layout();
+        let stack_start = (1 << 40) - GPA_STACK_SIZE;
+        let cases = [
+            (0x40_0000, 0x40_0000),
+            (0x7f00_0000_1000, stack_start - GPA_MAP_SIZE + 0x1000),
+            (0x7fff_0000_2000, stack_start + 0x2000),
+        ];
+        for (va, pa) in cases {
+            assert_eq!(layout.va_to_pa(VirtAddr::new(va)), Some(PhysAddr::new(pa)));
+            assert_eq!(layout.pa_to_va(PhysAddr::new(pa)), Some(VirtAddr::new(va)));
+        }
+
+        let zeroed = DuneLayout::default();
+        assert_eq!(zeroed.va_to_pa(VirtAddr::new(0x1000)), None);
+        assert_eq!(zeroed.pa_to_va(PhysAddr::new(0x1000)), None);
+    }
+
+    #[test]
+    fn vmpl_layout_and_page_perms() {
+        let mut vmpl = VmplLayout::new();
+        vmpl.set_phys_end(PhysAddr::new(1 << 40)).set_mmap_base(VirtAddr::new(0x1000_0000));
+        let layout = Layout::Vmpl(vmpl);
+        assert_eq!(layout.phys_limit(), PhysAddr::new(1 << 40));
+        assert_eq!(layout.mmap_base(), VirtAddr::new(0x1000_0000));
+        assert_eq!(Layout::Dune(dune_layout()).mmap_base(), VirtAddr::new(0x7f00_0000_0000));
+
+        let mock = MockBackend::with_layout(layout);
+        assert_eq!(mock.kind(), BackendKind::Vmpl);
+        mock.set_page_perm(VirtAddr::new(0x2000), 3, PAGE_PERM_READ | PAGE_PERM_WRITE).unwrap();
+        assert_eq!(
+            mock.page_perms(),
+            vec![MockPagePerm { va: 0x2000, nr_pages: 3, perm: PAGE_PERM_READ | PAGE_PERM_WRITE }]
+        );
+
+        /* no device behind fd -1: the ioctl fails and is reported by name */
+        let vmpl = VmplDevice { device: BaseDevice::new() };
+        let err = vmpl.set_page_perm(VirtAddr::new(0x2000), 1, PAGE_PERM_READ).unwrap_err();
+        assert!(matches!(err, Error::Ioctl { name: "VMPL_SET_PAGE_VMPL", errno: Errno::EBADF }));
+    }
+
+    #[test]
+    fn dune_set_page_perm_mprotects() {
+        let prot = prot_from_perm(PAGE_PERM_READ | PAGE_PERM_EXEC_USER);
+        assert_eq!(prot, libc::PROT_READ | libc::PROT_EXEC);
+        assert_eq!(prot_from_perm(0), libc::PROT_NONE);
+
+        let dune = DuneDevice { device: BaseDevice::new() };
+        let addr = mmap_pages(1).unwrap();
+        dune.set_page_perm(VirtAddr::new(addr), 1, PAGE_PERM_READ).unwrap();
+        let page = unsafe { std::slice::from_raw_parts(addr as *const u8, PAGE_SIZE as usize) };
+        assert!(page.iter().all(|b| *b == 0));
+
+        let err = dune.set_page_perm(VirtAddr::new(addr + 1), 1, PAGE_PERM_READ).unwrap_err();
+        assert!(matches!(err, Error::Memory { errno: Errno::EINVAL, .. }));
+        unsafe { libc::munmap(addr as *mut _, PAGE_SIZE as usize) };
+    }
+}
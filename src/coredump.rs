@@ -0,0 +1,318 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use libc::user_regs_struct;
+
+use crate::backend::{Layout, PAGE_PERM_EXEC_USER, PAGE_PERM_READ, PAGE_PERM_WRITE, PAGE_SIZE};
+use crate::dune::{DuneConfig, GPA_MAP_SIZE, GPA_STACK_SIZE};
+use crate::elf::{
+    ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_X86_64, ET_CORE, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE,
+};
+use crate::mem::GuestMemory;
+use crate::regs::GeneralRegisters;
+use crate::trap::DuneTf;
+use crate::{Error, Result};
+
+const ELF_HEADER_SIZE: u64 = 64;
+const ELF_PHDR_SIZE: u64 = 56;
+const NT_PRSTATUS: u32 = 1;
+
+/* struct elf_prstatus on x86_64 */
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REG: usize = 112;
+
+/* selectors of a 64-bit user context */
+const USER_CS: u64 = 0x33;
+const USER_SS: u64 = 0x2b;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CoreThread {
+    pub regs: GeneralRegisters,
+    pub cs: u64,
+    pub ss: u64,
+    pub signal: i32,
+    pub tid: i32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CoreSegment {
+    pub vaddr: u64,
+    pub len: u64,
+    pub perm: u32,
+}
+
+/// Builds an ELF core file (`ET_CORE`) from guest registers and memory.
+#[derive(Debug, Clone, Default)]
+pub struct CoreDumpBuilder {
+    threads: Vec<CoreThread>,
+    segments: Vec<CoreSegment>,
+}
+
+fn note_padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn page_align(value: u64) -> u64 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+impl CoreDumpBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_thread(&mut self, regs: &GeneralRegisters, signal: i32) -> &mut Self {
+        self.threads.push(CoreThread {
+            regs: *regs,
+            cs: USER_CS,
+            ss: USER_SS,
+            signal,
+            tid: std::process::id() as i32,
+        });
+        self
+    }
+
+    pub fn add_thread_from_config(&mut self, conf: &DuneConfig, signal: i32) -> &mut Self {
+        self.add_thread(&conf.general_registers(), signal)
+    }
+
+    pub fn add_thread_from_tf(&mut self, tf: &DuneTf, signal: i32) -> &mut Self {
+        self.add_thread(&tf.general_registers(), signal);
+        if let Some(thread) = self.threads.last_mut() {
+            thread.cs = tf.cs() as u64;
+            thread.ss = tf.ss() as u64;
+        }
+        self
+    }
+
+    pub fn add_segment(&mut self, vaddr: u64, len: u64, perm: u32) -> &mut Self {
+        self.segments.push(CoreSegment { vaddr, len, perm });
+        self
+    }
+
+    /// Add every readable host mapping that is part of the guest address space.
+    ///
+    /// Under Dune the whole process is guest memory; under VMPL only the
+    /// mmap window of the layout is.
+    pub fn add_mapped_segments(&mut self, layout: &Layout) -> Result<&mut Self> {
+        let ranges = match layout {
+            Layout::Dune(layout) => vec![
+                (0, layout.base_map().as_u64()),
+                (layout.base_map().as_u64(), layout.base_map().as_u64() + GPA_MAP_SIZE),
+                (layout.base_stack().as_u64(), layout.base_stack().as_u64() + GPA_STACK_SIZE),
+            ],
+            Layout::Vmpl(layout) => vec![(layout.mmap_base().as_u64(), layout.mmap_end().as_u64())],
+        };
+
+        let maps = fs::read_to_string("/proc/self/maps")?;
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(range), Some(perms)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let name = fields.nth(3).unwrap_or("");
+            if !perms.starts_with('r') || name == "[vvar]" || name == "[vsyscall]" {
+                continue;
+            }
+            let Some((start, end)) = range.split_once('-') else {
+                continue;
+            };
+            let (Ok(start), Ok(end)) = (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16)) else {
+                continue;
+            };
+
+            let mut perm = PAGE_PERM_READ;
+            if perms.as_bytes().get(1) == Some(&b'w') {
+                perm |= PAGE_PERM_WRITE;
+            }
+            if perms.as_bytes().get(2) == Some(&b'x') {
+                perm |= PAGE_PERM_EXEC_USER;
+            }
+            for (lo, hi) in &ranges {
+                let (lo, hi) = (start.max(*lo), end.min(*hi));
+                if lo < hi {
+                    self.add_segment(lo, hi - lo, perm);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    fn prstatus(thread: &CoreThread) -> [u8; PRSTATUS_SIZE] {
+        let mut desc = [0; PRSTATUS_SIZE];
+        desc[..4].copy_from_slice(&thread.signal.to_le_bytes());
+        desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&(thread.signal as i16).to_le_bytes());
+        desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&thread.tid.to_le_bytes());
+
+        let mut user: user_regs_struct = unsafe { std::mem::zeroed() };
+        thread.regs.write_user_regs(&mut user);
+        user.orig_rax = u64::MAX;
+        user.cs = thread.cs;
+        user.ss = thread.ss;
+
+        let regs = [
+            user.r15, user.r14, user.r13, user.r12, user.rbp, user.rbx, user.r11, user.r10,
+            user.r9, user.r8, user.rax, user.rcx, user.rdx, user.rsi, user.rdi, user.orig_rax,
+            user.rip, user.cs, user.eflags, user.rsp, user.ss, user.fs_base, user.gs_base,
+            user.ds, user.es, user.fs, user.gs,
+        ];
+        for (i, value) in regs.iter().enumerate() {
+            let off = PRSTATUS_REG + i * 8;
+            desc[off..off + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        desc
+    }
+
+    fn notes(&self) -> Vec<u8> {
+        let mut notes = Vec::new();
+        for thread in &self.threads {
+            let name = b"CORE\0";
+            notes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            notes.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+            notes.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+            notes.extend_from_slice(name);
+            notes.resize(note_padded(notes.len()), 0);
+            notes.extend_from_slice(&Self::prstatus(thread));
+            notes.resize(note_padded(notes.len()), 0);
+        }
+        notes
+    }
+
+    fn write_phdr<W: Write>(
+        w: &mut W,
+        p_type: u32,
+        flags: u32,
+        offset: u64,
+        vaddr: u64,
+        filesz: u64,
+        align: u64,
+    ) -> Result<()> {
+        w.write_all(&p_type.to_le_bytes())?;
+        w.write_all(&flags.to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        w.write_all(&vaddr.to_le_bytes())?;
+        w.write_all(&0u64.to_le_bytes())?; /* p_paddr */
+        w.write_all(&filesz.to_le_bytes())?;
+        w.write_all(&filesz.to_le_bytes())?; /* p_memsz */
+        w.write_all(&align.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Write the core file; pages that cannot be read are dumped as zeroes.
+    pub fn write<W: Write>(&self, w: &mut W, memory: &dyn GuestMemory) -> Result<()> {
+        if self.threads.is_empty() {
+            return Err(Error::InvalidInput("Core dump needs at least one thread".to_string()));
+        }
+
+        let notes = self.notes();
+        let phnum = 1 + self.segments.len() as u64;
+        let notes_offset = ELF_HEADER_SIZE + phnum * ELF_PHDR_SIZE;
+        let mut offset = page_align(notes_offset + notes.len() as u64);
+
+        /* ELF header */
+        w.write_all(&ELFMAG)?;
+        w.write_all(&[ELFCLASS64, ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0])?;
+        w.write_all(&ET_CORE.to_le_bytes())?;
+        w.write_all(&EM_X86_64.to_le_bytes())?;
+        w.write_all(&1u32.to_le_bytes())?;
+        w.write_all(&0u64.to_le_bytes())?; /* e_entry */
+        w.write_all(&ELF_HEADER_SIZE.to_le_bytes())?; /* e_phoff */
+        w.write_all(&0u64.to_le_bytes())?; /* e_shoff */
+        w.write_all(&0u32.to_le_bytes())?; /* e_flags */
+        w.write_all(&(ELF_HEADER_SIZE as u16).to_le_bytes())?;
+        w.write_all(&(ELF_PHDR_SIZE as u16).to_le_bytes())?;
+        w.write_all(&(phnum as u16).to_le_bytes())?;
+        w.write_all(&[0; 6])?; /* e_shentsize, e_shnum, e_shstrndx */
+
+        Self::write_phdr(w, PT_NOTE, 0, notes_offset, 0, notes.len() as u64, 4)?;
+        for segment in &self.segments {
+            let mut flags = 0;
+            if segment.perm & PAGE_PERM_READ != 0 {
+                flags |= PF_R;
+            }
+            if segment.perm & PAGE_PERM_WRITE != 0 {
+                flags |= PF_W;
+            }
+            if segment.perm & PAGE_PERM_EXEC_USER != 0 {
+                flags |= PF_X;
+            }
+            Self::write_phdr(w, PT_LOAD, flags, offset, segment.vaddr, segment.len, PAGE_SIZE)?;
+            offset = page_align(offset + segment.len);
+        }
+
+        w.write_all(&notes)?;
+        let mut written = notes_offset + notes.len() as u64;
+
+        let mut page = vec![0; PAGE_SIZE as usize];
+        for segment in &self.segments {
+            let start = page_align(written);
+            w.write_all(&vec![0; (start - written) as usize])?;
+            written = start;
+
+            let mut addr = segment.vaddr;
+            let end = segment.vaddr + segment.len;
+            while addr < end {
+                let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(end - addr) as usize;
+                if memory.read(addr, &mut page[..chunk]).is_err() {
+                    page[..chunk].fill(0);
+                }
+                w.write_all(&page[..chunk])?;
+                addr += chunk as u64;
+                written += chunk as u64;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, memory: &dyn GuestMemory) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write(&mut file, memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+
+    fn u16_at(buf: &[u8], off: usize) -> u16 {
+        u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], off: usize) -> u64 {
+        u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn core_layout() {
+        let mut memory = BufferMemory::new(0x400000, 0x2000);
+        memory.write(0x401000, b"guest").unwrap();
+
+        let mut regs = GeneralRegisters::new();
+        regs.set_rip(0x401000).set_rsp(0x7ffe0000);
+        let mut core = CoreDumpBuilder::new();
+        core.add_thread(&regs, libc::SIGSEGV)
+            .add_segment(0x400000, 0x2000, PAGE_PERM_READ | PAGE_PERM_EXEC_USER);
+
+        let mut out = Vec::new();
+        core.write(&mut out, &memory).unwrap();
+
+        assert_eq!(&out[..4], b"\x7fELF");
+        assert_eq!(u16_at(&out, 16), ET_CORE);
+        assert_eq!(u16_at(&out, 56), 2);
+
+        /* NT_PRSTATUS carries rip at pr_reg[16] */
+        let note = u64_at(&out, 64 + 8) as usize;
+        let desc = note + 12 + 8;
+        assert_eq!(u64_at(&out, desc + PRSTATUS_REG + 16 * 8), 0x401000);
+
+        let load = 64 + 56;
+        let data = u64_at(&out, load + 8) as usize;
+        assert_eq!(u64_at(&out, load + 16), 0x400000);
+        assert_eq!(&out[data + 0x1000..data + 0x1005], b"guest");
+    }
+}
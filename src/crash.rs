@@ -0,0 +1,475 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use x86_64::VirtAddr;
+
+use crate::backend::{Layout, PAGE_SIZE};
+use crate::dump::Rflags;
+use crate::dune::{DuneConfig, DuneRetCode};
+use crate::idt::IdtDescriptor;
+use crate::mem::GuestMemory;
+use crate::regs::{GeneralRegisters, Register};
+use crate::trap::DuneTf;
+use crate::tss::Tss;
+use crate::Result;
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+const DEFAULT_STACK_WORDS: usize = 32;
+const DEFAULT_HISTORY: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExitRecord {
+    pub ret: DuneRetCode,
+    pub rip: u64,
+    pub status: i64,
+}
+
+/// The last few VM exits seen by a run loop.
+#[derive(Debug, Clone)]
+pub struct ExitHistory {
+    capacity: usize,
+    exits: VecDeque<ExitRecord>,
+}
+
+impl ExitHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            exits: VecDeque::with_capacity(capacity.max(1)),
+        }
+    }
+
+    pub fn record(&mut self, conf: &DuneConfig) {
+        if self.exits.len() == self.capacity {
+            self.exits.pop_front();
+        }
+        self.exits.push_back(ExitRecord {
+            ret: DuneRetCode::from(conf.ret()),
+            rip: conf.rip(),
+            status: conf.status(),
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ExitRecord> {
+        self.exits.iter()
+    }
+}
+
+impl Default for ExitHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CrashReason {
+    UnhandledVmexit { status: i64 },
+    NoEnter { status: i64 },
+    Exception { vector: u8, err: u32 },
+}
+
+impl CrashReason {
+    /// The crash, if any, that a run loop exit represents.
+    pub fn from_config(conf: &DuneConfig) -> Option<Self> {
+        match DuneRetCode::from(conf.ret()) {
+            DuneRetCode::UnhandledVmexit => Some(CrashReason::UnhandledVmexit {
+                status: conf.status(),
+            }),
+            DuneRetCode::NoEnter => Some(CrashReason::NoEnter {
+                status: conf.status(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn from_trap_frame(tf: &DuneTf, vector: u8) -> Self {
+        CrashReason::Exception {
+            vector,
+            err: tf.err(),
+        }
+    }
+}
+
+/// Everything a crash report is built from; only the registers are required.
+pub struct CrashContext<'a> {
+    pub reason: CrashReason,
+    pub regs: GeneralRegisters,
+    pub extra: Vec<(&'static str, u64)>,
+    pub fault_addr: Option<u64>,
+    pub layout: Option<Layout>,
+    pub memory: Option<&'a dyn GuestMemory>,
+    pub idt: Option<&'a [IdtDescriptor]>,
+    pub tss: Option<&'a Tss>,
+    pub history: Option<&'a ExitHistory>,
+}
+
+impl<'a> CrashContext<'a> {
+    pub fn new(reason: CrashReason, regs: GeneralRegisters) -> Self {
+        Self {
+            reason,
+            regs,
+            extra: Vec::new(),
+            fault_addr: None,
+            layout: None,
+            memory: None,
+            idt: None,
+            tss: None,
+            history: None,
+        }
+    }
+
+    pub fn from_config(reason: CrashReason, conf: &DuneConfig) -> Self {
+        let mut ctx = Self::new(reason, conf.general_registers());
+        ctx.extra = vec![
+            ("cr3", conf.cr3()),
+            ("ret", conf.ret() as u64),
+            ("status", conf.status() as u64),
+            ("vcpu", conf.vcpu()),
+        ];
+        ctx
+    }
+
+    pub fn from_trap_frame(reason: CrashReason, tf: &DuneTf) -> Self {
+        let mut ctx = Self::new(reason, tf.general_registers());
+        ctx.extra = vec![("cs", tf.cs() as u64), ("ss", tf.ss() as u64), ("err", tf.err() as u64)];
+        ctx
+    }
+}
+
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_hex(out: &mut String, value: u64) {
+    let _ = write!(out, "\"{:#x}\"", value);
+}
+
+/// Look up the host mapping containing `addr` in `/proc/self/maps`.
+fn host_mapping(addr: u64) -> Option<String> {
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .find(|line| {
+            let range = line.split_whitespace().next().unwrap_or("");
+            let Some((start, end)) = range.split_once('-') else {
+                return false;
+            };
+            match (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16)) {
+                (Ok(start), Ok(end)) => (start..end).contains(&addr),
+                _ => false,
+            }
+        })
+        .map(|line| line.to_string())
+}
+
+/// Writes crash reports as JSON files into a directory.
+#[derive(Debug, Clone)]
+pub struct CrashReporter {
+    dir: PathBuf,
+    max_frames: usize,
+    stack_words: usize,
+}
+
+impl CrashReporter {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_frames: DEFAULT_MAX_FRAMES,
+            stack_words: DEFAULT_STACK_WORDS,
+        }
+    }
+
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    pub fn with_stack_words(mut self, stack_words: usize) -> Self {
+        self.stack_words = stack_words;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Walk the frame-pointer chain starting at the crashed RIP/RBP.
+    pub fn backtrace(&self, regs: &GeneralRegisters, memory: &dyn GuestMemory) -> Vec<u64> {
+        let mut frames = vec![regs.rip()];
+        let mut rbp = regs.rbp();
+        while frames.len() < self.max_frames && rbp != 0 && rbp.is_multiple_of(8) {
+            let Some(ret_addr) = rbp.checked_add(8) else {
+                break;
+            };
+            let (Ok(next), Ok(ret)) = (memory.read_u64(rbp), memory.read_u64(ret_addr)) else {
+                break;
+            };
+            if ret == 0 {
+                break;
+            }
+            frames.push(ret);
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        frames
+    }
+
+    pub fn to_json(&self, ctx: &CrashContext<'_>) -> String {
+        let mut out = String::from("{\n");
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let _ = writeln!(out, "  \"timestamp\": {},", timestamp);
+        let _ = writeln!(out, "  \"pid\": {},", std::process::id());
+
+        out.push_str("  \"reason\": {");
+        match ctx.reason {
+            CrashReason::UnhandledVmexit { status } => {
+                let _ = write!(out, "\"kind\": \"unhandled_vmexit\", \"status\": {}", status);
+            }
+            CrashReason::NoEnter { status } => {
+                let _ = write!(out, "\"kind\": \"noenter\", \"status\": {}", status);
+            }
+            CrashReason::Exception { vector, err } => {
+                let _ = write!(out, "\"kind\": \"exception\", \"vector\": {}, \"err\": ", vector);
+                json_hex(&mut out, err as u64);
+            }
+        }
+        out.push_str("},\n");
+
+        out.push_str("  \"registers\": {");
+        for (i, reg) in Register::ALL.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "\"{}\": ", reg);
+            json_hex(&mut out, ctx.regs[*reg]);
+        }
+        for (name, value) in &ctx.extra {
+            let _ = write!(out, ", \"{}\": ", name);
+            json_hex(&mut out, *value);
+        }
+        out.push_str("},\n  \"rflags\": ");
+        json_str(&mut out, &Rflags(ctx.regs.rflags()).to_string());
+        out.push_str(",\n");
+
+        if let Some(memory) = ctx.memory {
+            out.push_str("  \"backtrace\": [");
+            for (i, frame) in self.backtrace(&ctx.regs, memory).iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                json_hex(&mut out, *frame);
+            }
+            out.push_str("],\n  \"stack\": [");
+            let rsp = ctx.regs.rsp();
+            for i in 0..self.stack_words as u64 {
+                let addr = i.checked_mul(8).and_then(|off| rsp.checked_add(off));
+                let Some(Ok(word)) = addr.map(|addr| memory.read_u64(addr)) else {
+                    break;
+                };
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                json_hex(&mut out, word);
+            }
+            out.push_str("],\n");
+        }
+
+        if let Some(addr) = ctx.fault_addr {
+            out.push_str("  \"fault\": {\"address\": ");
+            json_hex(&mut out, addr);
+            out.push_str(", \"page\": ");
+            json_hex(&mut out, addr & !(PAGE_SIZE - 1));
+            if let Some(layout) = ctx.layout {
+                let region = match layout {
+                    Layout::Dune(layout) => {
+                        if let Some(gpa) = layout.va_to_pa(VirtAddr::new_truncate(addr)) {
+                            out.push_str(", \"gpa\": ");
+                            json_hex(&mut out, gpa.as_u64());
+                        }
+                        if addr >= layout.base_stack().as_u64() {
+                            "stack"
+                        } else if addr >= layout.base_map().as_u64() {
+                            "mmap"
+                        } else {
+                            "identity"
+                        }
+                    }
+                    Layout::Vmpl(layout) => {
+                        if (layout.mmap_base().as_u64()..layout.mmap_end().as_u64()).contains(&addr) {
+                            "mmap"
+                        } else {
+                            "other"
+                        }
+                    }
+                };
+                let _ = write!(out, ", \"region\": \"{}\"", region);
+            }
+            if let Some(mapping) = host_mapping(addr) {
+                out.push_str(", \"host_mapping\": ");
+                json_str(&mut out, &mapping);
+            }
+            out.push_str("},\n");
+        }
+
+        if let Some(idt) = ctx.idt {
+            out.push_str("  \"idt\": [");
+            let present = idt.iter().enumerate().filter(|(_, desc)| desc.is_present());
+            for (i, (vector, desc)) in present.enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                let _ = write!(out, "{{\"vector\": {}, \"handler\": ", vector);
+                json_hex(&mut out, desc.idt_addr() as u64);
+                let _ = write!(
+                    out,
+                    ", \"selector\": {}, \"ist\": {}, \"type_attr\": {}}}",
+                    desc.selector(),
+                    desc.ist(),
+                    desc.type_attr()
+                );
+            }
+            out.push_str("],\n");
+        }
+
+        if let Some(tss) = ctx.tss {
+            out.push_str("  \"tss\": {\"rsp\": [");
+            for i in 0..3 {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                json_hex(&mut out, tss.tss_rsp(i));
+            }
+            out.push_str("], \"ist\": [");
+            /* IST1-IST7, in tss_ist[0..7] */
+            for i in 0..7 {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                json_hex(&mut out, tss.tss_ist(i));
+            }
+            let _ = writeln!(out, "], \"iomb\": {}}},", tss.tss_iomb());
+        }
+
+        if let Some(history) = ctx.history {
+            out.push_str("  \"exit_history\": [");
+            for (i, exit) in history.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                let _ = write!(out, "{{\"ret\": \"{}\", \"rip\": ", exit.ret.name());
+                json_hex(&mut out, exit.rip);
+                let _ = write!(out, ", \"status\": {}}}", exit.status);
+            }
+            out.push_str("],\n");
+        }
+
+        let _ = writeln!(out, "  \"version\": 1\n}}");
+        out
+    }
+
+    /// Write the report and return its path.
+    pub fn write(&self, ctx: &CrashContext<'_>) -> Result<PathBuf> {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+
+        fs::create_dir_all(&self.dir)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let path = self.dir.join(format!(
+            "crash-{}-{}-{}.json",
+            timestamp,
+            std::process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, self.to_json(ctx))?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+
+    #[test]
+    fn report_walks_frame_pointers() {
+        let mut memory = BufferMemory::new(0x8000, 0x100);
+        /* frame at 0x8040 -> frame at 0x8080 -> end of chain */
+        memory.write_u64(0x8040, 0x8080).unwrap();
+        memory.write_u64(0x8048, 0x401234).unwrap();
+        memory.write_u64(0x8080, 0).unwrap();
+        memory.write_u64(0x8088, 0x405678).unwrap();
+
+        let mut regs = GeneralRegisters::new();
+        regs.set_rip(0x401000).set_rbp(0x8040).set_rsp(0x8020).set_rflags(0x246);
+
+        let mut conf = DuneConfig::default();
+        conf.set_ret(crate::DUNE_RET_UNHANDLED_VMEXIT);
+        let reason = CrashReason::from_config(&conf).unwrap();
+        let mut ctx = CrashContext::new(reason, regs);
+        ctx.memory = Some(&memory);
+
+        let reporter = CrashReporter::new(std::env::temp_dir()).with_stack_words(2);
+        assert_eq!(reporter.backtrace(&regs, &memory), vec![0x401000, 0x401234, 0x405678]);
+
+        let json = reporter.to_json(&ctx);
+        assert!(json.contains("\"kind\": \"unhandled_vmexit\""));
+        assert!(json.contains("\"backtrace\": [\"0x401000\", \"0x401234\", \"0x405678\"]"));
+        assert!(json.contains("\"rflags\": \"[ PF ZF IF ]\""));
+    }
+
+    #[test]
+    fn registers_at_top_of_address_space() {
+        let base = 0xffff_ffff_ffff_ff00;
+        let mut memory = BufferMemory::new(base, 0x100);
+        memory.write_u64(0xffff_ffff_ffff_fff0, 0x1111).unwrap();
+        memory.write_u64(0xffff_ffff_ffff_fff8, 0x2222).unwrap();
+
+        let mut regs = GeneralRegisters::new();
+        regs.set_rip(0x401000).set_rbp(0xffff_ffff_ffff_fff8).set_rsp(0xffff_ffff_ffff_fff0);
+
+        let mut conf = DuneConfig::default();
+        conf.set_ret(crate::DUNE_RET_UNHANDLED_VMEXIT);
+        let mut ctx = CrashContext::new(CrashReason::from_config(&conf).unwrap(), regs);
+        ctx.memory = Some(&memory);
+
+        let reporter = CrashReporter::new(std::env::temp_dir()).with_stack_words(4);
+        assert_eq!(reporter.backtrace(&regs, &memory), vec![0x401000]);
+        let json = reporter.to_json(&ctx);
+        assert!(json.contains("\"stack\": [\"0x1111\", \"0x2222\"]"));
+    }
+
+    #[test]
+    fn report_lists_all_ist_slots() {
+        let mut tss = Tss::default();
+        tss.set_tss_ist(0, 0x1000).set_tss_ist(6, 0x7000);
+
+        let mut conf = DuneConfig::default();
+        conf.set_ret(crate::DUNE_RET_UNHANDLED_VMEXIT);
+        let mut ctx = CrashContext::new(CrashReason::from_config(&conf).unwrap(), GeneralRegisters::new());
+        ctx.tss = Some(&tss);
+
+        let json = CrashReporter::new(std::env::temp_dir()).to_json(&ctx);
+        assert!(json.contains("\"ist\": [\"0x1000\", \"0x0\", \"0x0\", \"0x0\", \"0x0\", \"0x0\", \"0x7000\"]"));
+    }
+}
@@ -1,6 +1,8 @@
 
+use std::arch::asm;
 use std::ffi::c_void;
-use crate::funcs;
+use crate::abi::abi_layout;
+use crate::{funcs, offset_consts};
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -25,6 +27,10 @@ pub struct DuneTrapRegs {
     rflags: u64,
 }
 
+abi_layout!(DuneTrapRegs => dune_trap_regs {
+    rax, rbx, rcx, rdx, rsi, rdi, rsp, rbp, r8, r9, r10, r11, r12, r13, r14, r15, rip, rflags,
+});
+
 impl DuneTrapRegs {
     funcs!(rax, u64);
     funcs!(rbx, u64);
@@ -46,6 +52,67 @@ impl DuneTrapRegs {
     funcs!(rflags, u64);
 }
 
+offset_consts!(DuneTrapRegs, DUNE_TRAP_REGS {
+    rax, rbx, rcx, rdx, rsi, rdi, rsp, rbp, r8, r9, r10, r11, r12, r13, r14, r15, rip, rflags,
+});
+
+/// Load the register file in `regs` and continue at `regs.rip`.
+///
+/// The return address and RFLAGS are staged below the red zone of the
+/// target stack so that nothing the interrupted code owns is clobbered.
+///
+/// # Safety
+///
+/// `regs` must describe a valid context to resume, with a mapped stack.
+pub unsafe fn dune_trap_resume(regs: *const DuneTrapRegs) -> ! {
+    asm!(
+        "mov rsp, [rax + {rsp}]",
+        "sub rsp, {redzone} + 16",
+        "mov rcx, [rax + {rip}]",
+        "mov [rsp + 8], rcx",
+        "mov rcx, [rax + {rflags}]",
+        "mov [rsp], rcx",
+        "mov rbx, [rax + {rbx}]",
+        "mov rdx, [rax + {rdx}]",
+        "mov rsi, [rax + {rsi}]",
+        "mov rdi, [rax + {rdi}]",
+        "mov rbp, [rax + {rbp}]",
+        "mov r8, [rax + {r8}]",
+        "mov r9, [rax + {r9}]",
+        "mov r10, [rax + {r10}]",
+        "mov r11, [rax + {r11}]",
+        "mov r12, [rax + {r12}]",
+        "mov r13, [rax + {r13}]",
+        "mov r14, [rax + {r14}]",
+        "mov r15, [rax + {r15}]",
+        "mov rcx, [rax + {rcx}]",
+        "mov rax, [rax + {rax}]",
+        "popfq",
+        "ret {redzone}",
+        in("rax") regs,
+        rax = const DUNE_TRAP_REGS_RAX,
+        rbx = const DUNE_TRAP_REGS_RBX,
+        rcx = const DUNE_TRAP_REGS_RCX,
+        rdx = const DUNE_TRAP_REGS_RDX,
+        rsi = const DUNE_TRAP_REGS_RSI,
+        rdi = const DUNE_TRAP_REGS_RDI,
+        rsp = const DUNE_TRAP_REGS_RSP,
+        rbp = const DUNE_TRAP_REGS_RBP,
+        r8 = const DUNE_TRAP_REGS_R8,
+        r9 = const DUNE_TRAP_REGS_R9,
+        r10 = const DUNE_TRAP_REGS_R10,
+        r11 = const DUNE_TRAP_REGS_R11,
+        r12 = const DUNE_TRAP_REGS_R12,
+        r13 = const DUNE_TRAP_REGS_R13,
+        r14 = const DUNE_TRAP_REGS_R14,
+        r15 = const DUNE_TRAP_REGS_R15,
+        rip = const DUNE_TRAP_REGS_RIP,
+        rflags = const DUNE_TRAP_REGS_RFLAGS,
+        redzone = const 128,
+        options(noreturn),
+    )
+}
+
 pub type DuneTrapNotifyFunc = extern "C" fn(*mut DuneTrapRegs, *mut c_void) -> !;
 
 #[no_mangle]
@@ -68,6 +135,10 @@ pub struct DuneTrapConfig {
     pub delay: u8,
 }
 
+abi_layout!(DuneTrapConfig => dune_trap_config {
+    trigger_rip, notify_func, regs, regs_size, priv_data as priv_, delay,
+});
+
 impl DuneTrapConfig {
     funcs!(trigger_rip, u64);
     funcs!(notify_func, DuneTrapNotifyFunc);
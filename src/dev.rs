@@ -1,3 +1,5 @@
+use std::ffi::CString;
+
 use libc::c_int;
 use nix::ioctl_none;
 use nix::ioctl_read;
@@ -6,6 +8,8 @@ use nix::errno::Errno;
 use nix::ioctl_write_ptr;
 
 use crate::dune::DuneConfig;
+use crate::instrument::trace_event;
+use crate::ioctl::ioctl_name;
 use crate::dune::DuneLayout;
 use crate::vmpl::VmplLayout;
 use crate::vmpl::VmplArgs;
@@ -25,11 +29,15 @@ use crate::Result;
 /* FIXME: this must be reserved in miscdevice.h */
 pub const DUNE_MINOR: u32 = 233;
 
-const DUNE_IOC_MAGIC: u8 = b'd';
+pub const DUNE_DEVICE_PATH: &str = "/dev/dune";
+pub const VMPL_DEVICE_PATH: &str = "/dev/vmpl";
+
+pub(crate) const DUNE_IOC_MAGIC: u8 = b'd';
 
 pub const IOCTL_DUNE_ENTER: u64 = 0xc0b0e901;
 
-ioctl_read!(dune_enter, DUNE_IOC_MAGIC, 0x01, DuneConfig);
+/* the module keys DUNE_ENTER on DUNE_MINOR, unlike the other Dune ioctls */
+ioctl_readwrite!(dune_enter, DUNE_MINOR, 0x01, DuneConfig);
 ioctl_read!(dune_get_syscall, DUNE_IOC_MAGIC,0x02, u64);
 ioctl_read!(dune_get_layout, DUNE_IOC_MAGIC, 0x03, DuneLayout);
 ioctl_readwrite!(dune_trap_enable, DUNE_IOC_MAGIC, 0x04, DuneTrapConfig);
@@ -37,7 +45,7 @@ ioctl_none!(dune_trap_disable, DUNE_IOC_MAGIC, 0x05);
 
 pub const DUNE_SIGNAL_INTR_BASE: u64 = 200;
 
-const VMPL_IOCTL_MAGIC: u8 = b'k';
+pub(crate) const VMPL_IOCTL_MAGIC: u8 = b'k';
 
 ioctl_read!(vmpl_get_layout, VMPL_IOCTL_MAGIC, 0x01, VmplLayout);
 ioctl_none!(vmpl_create_vm, VMPL_IOCTL_MAGIC, 0x10);
@@ -56,7 +64,7 @@ pub trait Device : Send + Sync {
     fn fd(&self) -> c_int;
     fn open(&mut self, path: &str) -> Result<i32>;
     fn close(&self) -> Result<i32>;
-    fn ioctl<T>(&self, request: i32, arg: *mut T) -> Result<i32>;
+    fn ioctl<T>(&self, request: u64, arg: *mut T) -> Result<i32>;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -79,10 +87,15 @@ impl Device for BaseDevice {
     }
 
     fn open(&mut self, path: &str) -> Result<i32> {
-        let fd = unsafe { libc::open(path.as_ptr() as *const i8, libc::O_RDWR) };
+        let cpath = CString::new(path)
+            .map_err(|_| crate::Error::InvalidInput(format!("Device path contains NUL: {}", path)))?;
+        let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_RDWR) };
         if fd < 0 {
-            return Err(crate::Error::LibcError(Errno::last()));
+            let err = crate::Error::last_device(path);
+            trace_event!(WARN, path, error = %err, "failed to open device");
+            return Err(err);
         }
+        trace_event!(DEBUG, path, fd, "opened device");
         self.fd = fd;
         Ok(fd)
     }
@@ -91,17 +104,28 @@ impl Device for BaseDevice {
         unsafe {
             let ret = libc::close(self.fd);
             if ret < 0 {
-                return Err(crate::Error::LibcError(Errno::last()));
+                return Err(crate::Error::last_syscall("close"));
             }
         }
         Ok(0)
     }
 
-    fn ioctl<T>(&self, request: i32, arg: *mut T) -> Result<i32> {
+    fn ioctl<T>(&self, request: u64, arg: *mut T) -> Result<i32> {
         unsafe {
-            let ret = libc::ioctl(self.fd, request, arg);
+            let ret = libc::ioctl(self.fd, request as libc::Ioctl, arg);
             if ret < 0 {
-                return Err(crate::Error::LibcError(Errno::last()));
+                let errno = Errno::last();
+                trace_event!(
+                    WARN,
+                    fd = self.fd,
+                    request = %crate::ioctl::describe(request),
+                    %errno,
+                    "ioctl failed"
+                );
+                return Err(match ioctl_name(request) {
+                    Some(name) => crate::Error::Ioctl { name, errno },
+                    None => crate::Error::LibcError(errno),
+                });
             }
         }
         Ok(0)
@@ -164,7 +188,7 @@ impl Device for BaseSystem {
         self.device.close()
     }
 
-    fn ioctl<T>(&self, request: i32, arg: *mut T) -> Result<i32> {
+    fn ioctl<T>(&self, request: u64, arg: *mut T) -> Result<i32> {
         self.device.ioctl(request, arg)
     }
 }
\ No newline at end of file
@@ -0,0 +1,202 @@
+use std::fmt;
+
+use crate::debug::DuneTrapRegs;
+use crate::dune::DuneConfig;
+use crate::regs::{GeneralRegisters, Register};
+use crate::trap::DuneTf;
+
+const DUMP_COLUMNS: usize = 3;
+
+const RFLAGS_BITS: [(u32, &str); 16] = [
+    (0, "CF"),
+    (2, "PF"),
+    (4, "AF"),
+    (6, "ZF"),
+    (7, "SF"),
+    (8, "TF"),
+    (9, "IF"),
+    (10, "DF"),
+    (11, "OF"),
+    (14, "NT"),
+    (16, "RF"),
+    (17, "VM"),
+    (18, "AC"),
+    (19, "VIF"),
+    (20, "VIP"),
+    (21, "ID"),
+];
+
+/// RFLAGS value that prints its set bits the way gdb does, e.g. `[ PF ZF IF ]`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rflags(pub u64);
+
+impl Rflags {
+    pub fn iopl(&self) -> u8 {
+        ((self.0 >> 12) & 0x3) as u8
+    }
+
+    pub fn flags(&self) -> impl Iterator<Item = &'static str> + '_ {
+        RFLAGS_BITS
+            .iter()
+            .filter(move |(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl fmt::Display for Rflags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[ ")?;
+        for flag in self.flags() {
+            write!(f, "{} ", flag)?;
+        }
+        if self.iopl() != 0 {
+            write!(f, "IOPL={} ", self.iopl())?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Hex register grid for crash reports and traces.
+#[derive(Debug, Clone)]
+pub struct RegisterDump {
+    regs: GeneralRegisters,
+    extra: Vec<(&'static str, u64)>,
+}
+
+impl RegisterDump {
+    pub fn new(regs: &GeneralRegisters) -> Self {
+        Self {
+            regs: *regs,
+            extra: Vec::new(),
+        }
+    }
+
+    /// Append a non-general register, e.g. a segment selector or cr3.
+    pub fn with(mut self, name: &'static str, value: u64) -> Self {
+        self.extra.push((name, value));
+        self
+    }
+
+    pub fn registers(&self) -> &GeneralRegisters {
+        &self.regs
+    }
+}
+
+impl From<&DuneConfig> for RegisterDump {
+    fn from(conf: &DuneConfig) -> Self {
+        RegisterDump::new(&conf.general_registers())
+            .with("cr3", conf.cr3())
+            .with("ret", conf.ret() as u64)
+            .with("status", conf.status() as u64)
+            .with("vcpu", conf.vcpu())
+    }
+}
+
+impl From<&DuneTf> for RegisterDump {
+    fn from(tf: &DuneTf) -> Self {
+        RegisterDump::new(&tf.general_registers())
+            .with("cs", tf.cs() as u64)
+            .with("ss", tf.ss() as u64)
+            .with("err", tf.err() as u64)
+    }
+}
+
+impl From<&DuneTrapRegs> for RegisterDump {
+    fn from(regs: &DuneTrapRegs) -> Self {
+        RegisterDump::new(&GeneralRegisters::from(regs))
+    }
+}
+
+impl fmt::Display for RegisterDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cells: Vec<(&str, u64)> = Register::ALL
+            .iter()
+            .filter(|reg| **reg != Register::Rflags)
+            .map(|reg| (reg.name(), self.regs[*reg]))
+            .chain(self.extra.iter().copied())
+            .collect();
+
+        for row in cells.chunks(DUMP_COLUMNS) {
+            for (i, (name, value)) in row.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "  ")?;
+                }
+                write!(f, "{:<6} {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        let rflags = self.regs.rflags();
+        writeln!(f, "{:<6} {:#018x} {}", "rflags", rflags, Rflags(rflags))
+    }
+}
+
+/// Registers that differ between two snapshots, one `old -> new` line each.
+#[derive(Debug, Clone)]
+pub struct RegisterDiff {
+    before: GeneralRegisters,
+    after: GeneralRegisters,
+}
+
+impl RegisterDiff {
+    pub fn new(before: &GeneralRegisters, after: &GeneralRegisters) -> Self {
+        Self {
+            before: *before,
+            after: *after,
+        }
+    }
+
+    pub fn changed(&self) -> impl Iterator<Item = (Register, u64, u64)> + '_ {
+        Register::ALL
+            .into_iter()
+            .filter(move |reg| self.before[*reg] != self.after[*reg])
+            .map(move |reg| (reg, self.before[reg], self.after[reg]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed().next().is_none()
+    }
+}
+
+impl fmt::Display for RegisterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "(no register changes)");
+        }
+        for (reg, old, new) in self.changed() {
+            write!(f, "{:<6} {:#018x} -> {:#018x}", reg.name(), old, new)?;
+            if reg == Register::Rflags {
+                write!(f, " {} -> {}", Rflags(old), Rflags(new))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rflags_decoding() {
+        assert_eq!(Rflags(0x246).to_string(), "[ PF ZF IF ]");
+        assert_eq!(Rflags(0x3001).to_string(), "[ CF IOPL=3 ]");
+    }
+
+    #[test]
+    fn dump_and_diff() {
+        let mut before = GeneralRegisters::new();
+        before.set_rflags(0x202);
+        let mut after = before;
+        after.set_rax(0x10).set_rflags(0x246);
+
+        let dump = RegisterDump::new(&after).to_string();
+        assert!(dump.starts_with("rax    0x0000000000000010  rbx"));
+        assert!(dump.ends_with("rflags 0x0000000000000246 [ PF ZF IF ]\n"));
+
+        let diff = RegisterDiff::new(&before, &after).to_string();
+        assert_eq!(diff.lines().count(), 2);
+        assert!(diff.contains("rax    0x0000000000000000 -> 0x0000000000000010"));
+        assert!(diff.contains("[ IF ] -> [ PF ZF IF ]"));
+    }
+}
@@ -1,6 +1,5 @@
-use std::mem::offset_of;
-
-use crate::funcs;
+use crate::abi::abi_layout;
+use crate::{funcs, offset_consts};
 use x86_64::{PhysAddr, VirtAddr};
 
 #[repr(C)]
@@ -30,8 +29,15 @@ pub struct DuneConfig {
     vcpu: u64,
 }
 
+abi_layout!(DuneConfig => dune_config {
+    ret, rax, rbx, rcx, rdx, rsi, rdi, rsp, rbp, r8, r9, r10, r11, r12, r13, r14, r15,
+    rip, rflags, cr3, status, vcpu,
+});
+
 impl DuneConfig {
     funcs!(rax, i64);
+    funcs!(rbx, u64);
+    funcs!(rbp, u64);
     funcs!(rdi, u64);
     funcs!(rsi, u64);
     funcs!(rdx, u64);
@@ -39,6 +45,11 @@ impl DuneConfig {
     funcs!(r8, u64);
     funcs!(r9, u64);
     funcs!(r10, u64);
+    funcs!(r11, u64);
+    funcs!(r12, u64);
+    funcs!(r13, u64);
+    funcs!(r14, u64);
+    funcs!(r15, u64);
     funcs!(ret, i64);
     funcs!(rip, u64);
     funcs!(rsp, u64);
@@ -48,28 +59,10 @@ impl DuneConfig {
     funcs!(vcpu, u64);
 }
 
-pub const DUNE_CFG_RET: usize = offset_of!(DuneConfig, ret);
-pub const DUNE_CFG_RAX: usize = offset_of!(DuneConfig, rax);
-pub const DUNE_CFG_RBX: usize = offset_of!(DuneConfig, rbx);
-pub const DUNE_CFG_RCX: usize = offset_of!(DuneConfig, rcx);
-pub const DUNE_CFG_RDX: usize = offset_of!(DuneConfig, rdx);
-pub const DUNE_CFG_RSI: usize = offset_of!(DuneConfig, rsi);
-pub const DUNE_CFG_RDI: usize = offset_of!(DuneConfig, rdi);
-pub const DUNE_CFG_RSP: usize = offset_of!(DuneConfig, rsp);
-pub const DUNE_CFG_RBP: usize = offset_of!(DuneConfig, rbp);
-pub const DUNE_CFG_R8: usize = offset_of!(DuneConfig, r8);
-pub const DUNE_CFG_R9: usize = offset_of!(DuneConfig, r9);
-pub const DUNE_CFG_R10: usize = offset_of!(DuneConfig, r10);
-pub const DUNE_CFG_R11: usize = offset_of!(DuneConfig, r11);
-pub const DUNE_CFG_R12: usize = offset_of!(DuneConfig, r12);
-pub const DUNE_CFG_R13: usize = offset_of!(DuneConfig, r13);
-pub const DUNE_CFG_R14: usize = offset_of!(DuneConfig, r14);
-pub const DUNE_CFG_R15: usize = offset_of!(DuneConfig, r15);
-pub const DUNE_CFG_RIP: usize = offset_of!(DuneConfig, rip);
-pub const DUNE_CFG_RFLAGS: usize = offset_of!(DuneConfig, rflags);
-pub const DUNE_CFG_CR3: usize = offset_of!(DuneConfig, cr3);
-pub const DUNE_CFG_STATUS: usize = offset_of!(DuneConfig, status);
-pub const DUNE_CFG_VCPU: usize = offset_of!(DuneConfig, vcpu);
+offset_consts!(DuneConfig, DUNE_CFG {
+    ret, rax, rbx, rcx, rdx, rsi, rdi, rsp, rbp, r8, r9, r10, r11, r12, r13, r14, r15,
+    rip, rflags, cr3, status, vcpu,
+});
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -79,19 +72,56 @@ pub struct DuneLayout {
     base_stack: VirtAddr,
 }
 
+abi_layout!(DuneLayout => dune_layout { phys_limit, base_map, base_stack });
+
 impl DuneLayout {
     funcs!(phys_limit, PhysAddr);
     funcs!(base_map, VirtAddr);
     funcs!(base_stack, VirtAddr);
 
-    #[allow(dead_code)]
-    fn new(phys_limit: PhysAddr, base_map: VirtAddr, base_stack: VirtAddr) -> Self {
+    pub fn new(phys_limit: PhysAddr, base_map: VirtAddr, base_stack: VirtAddr) -> Self {
         Self {
             phys_limit,
             base_map,
             base_stack,
         }
     }
+
+    /// Translate a host virtual address to the guest physical address Dune maps it at.
+    ///
+    /// `None` if the layout cannot hold the stack and map regions, or the
+    /// result is not a valid physical address.
+    pub fn va_to_pa(&self, va: VirtAddr) -> Option<PhysAddr> {
+        let (map_start, stack_start) = self.gpa_regions()?;
+        let pa = if va >= self.base_stack {
+            (va - self.base_stack).checked_add(stack_start)?
+        } else if va >= self.base_map {
+            (va - self.base_map).checked_add(map_start)?
+        } else {
+            va.as_u64()
+        };
+        PhysAddr::try_new(pa).ok()
+    }
+
+    /// Inverse of `va_to_pa`.
+    pub fn pa_to_va(&self, pa: PhysAddr) -> Option<VirtAddr> {
+        let (map_start, stack_start) = self.gpa_regions()?;
+        let pa = pa.as_u64();
+        let va = if pa >= stack_start {
+            self.base_stack.as_u64().checked_add(pa - stack_start)?
+        } else if pa >= map_start {
+            self.base_map.as_u64().checked_add(pa - map_start)?
+        } else {
+            pa
+        };
+        VirtAddr::try_new(va).ok()
+    }
+
+    /// Guest physical start of the map and stack regions, just below `phys_limit`.
+    fn gpa_regions(&self) -> Option<(u64, u64)> {
+        let stack_start = self.phys_limit.as_u64().checked_sub(GPA_STACK_SIZE)?;
+        Some((stack_start.checked_sub(GPA_MAP_SIZE)?, stack_start))
+    }
 }
 
 impl Default for DuneLayout {
@@ -116,6 +146,7 @@ pub const DUNE_RET_EPT_VIOLATION: i64 = 5;
 pub const DUNE_RET_NOENTER: i64 = 6;
 pub const DUNE_RET_UNHANDLED_VMEXIT: i64 = 7;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum DuneRetCode {
     None = 0,
     Exit = 1,
@@ -142,4 +173,20 @@ impl From<i64> for DuneRetCode {
             _ => DuneRetCode::Unknown,
         }
     }
-}
\ No newline at end of file
+}
+
+impl DuneRetCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DuneRetCode::None => "none",
+            DuneRetCode::Exit => "exit",
+            DuneRetCode::Syscall => "syscall",
+            DuneRetCode::Interrupt => "interrupt",
+            DuneRetCode::Signal => "signal",
+            DuneRetCode::EptViolation => "ept_violation",
+            DuneRetCode::NoEnter => "noenter",
+            DuneRetCode::UnhandledVmexit => "unhandled_vmexit",
+            DuneRetCode::Unknown => "unknown",
+        }
+    }
+}
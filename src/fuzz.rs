@@ -0,0 +1,307 @@
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::backend::{Backend, PAGE_SIZE};
+use crate::dune::{DuneConfig, DuneLayout, DuneRetCode};
+use crate::mem::GuestMemory;
+use crate::snapshot::Snapshot;
+use crate::Result;
+
+type PhysToVirt = Box<dyn Fn(PhysAddr) -> Option<VirtAddr> + Send + Sync>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirtyPage {
+    pub va: u64,
+    pub size: u64,
+}
+
+/// Finds written pages through the dirty bits of the guest page tables.
+///
+/// Clearing the bits only takes effect once the guest TLB is flushed, which
+/// happens when the vCPU reloads CR3 on the next entry.
+pub struct DirtyTracker {
+    cr3: PhysAddr,
+    phys_to_virt: PhysToVirt,
+}
+
+impl DirtyTracker {
+    /// # Safety
+    ///
+    /// `cr3` must be the root of a live four-level page table, and
+    /// `phys_to_virt` must map each table's physical address to where it is
+    /// mapped in this process. Tables it cannot map are skipped.
+    pub unsafe fn new<F>(cr3: u64, phys_to_virt: F) -> Self
+    where
+        F: Fn(PhysAddr) -> Option<VirtAddr> + Send + Sync + 'static,
+    {
+        Self {
+            cr3: PhysAddr::new_truncate(cr3),
+            phys_to_virt: Box::new(phys_to_virt),
+        }
+    }
+
+    /// Track the page tables Dune builds, using the layout to find them.
+    ///
+    /// # Safety
+    ///
+    /// See `DirtyTracker::new`.
+    pub unsafe fn for_dune(cr3: u64, layout: DuneLayout) -> Self {
+        Self::new(cr3, move |pa| layout.pa_to_va(pa))
+    }
+
+    fn table(&self, pa: PhysAddr) -> Option<*mut PageTable> {
+        (self.phys_to_virt)(pa).map(|va| va.as_mut_ptr())
+    }
+
+    fn walk(&self, table: PhysAddr, level: u32, base: u64, clear: bool, out: &mut Vec<DirtyPage>) {
+        let shift = 12 + 9 * (level - 1);
+        let Some(table) = self.table(table) else {
+            return;
+        };
+        let table = unsafe { &mut *table };
+        for (i, entry) in table.iter_mut().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let va = VirtAddr::new_truncate(base | (i as u64) << shift).as_u64();
+            let leaf = level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE));
+            if !leaf {
+                self.walk(entry.addr(), level - 1, va, clear, out);
+                continue;
+            }
+            if flags.contains(PageTableFlags::DIRTY) {
+                out.push(DirtyPage { va, size: 1 << shift });
+                if clear {
+                    entry.set_flags(flags - PageTableFlags::DIRTY);
+                }
+            }
+        }
+    }
+
+    /// List dirty leaf pages, optionally clearing their dirty bits.
+    pub fn collect(&self, clear: bool) -> Vec<DirtyPage> {
+        let mut out = Vec::new();
+        self.walk(self.cr3, 4, 0, clear, &mut out);
+        out
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FuzzOutcome {
+    Ok,
+    Crash { ret: DuneRetCode, status: i64 },
+}
+
+/// What the run loop does after an exit the harness does not finish on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitAction {
+    Resume,
+    Stop,
+}
+
+/// Guest buffer the input is copied into; its address and length are
+/// passed in rdi/rsi, like `LLVMFuzzerTestOneInput(data, size)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FuzzInput {
+    pub addr: u64,
+    pub max_len: usize,
+}
+
+type ExitHandler = Box<dyn FnMut(&mut DuneConfig) -> ExitAction + Send>;
+
+fn default_exit_handler(conf: &mut DuneConfig) -> ExitAction {
+    match DuneRetCode::from(conf.ret()) {
+        DuneRetCode::None | DuneRetCode::Interrupt => ExitAction::Resume,
+        _ => ExitAction::Stop,
+    }
+}
+
+/// Runs one input per guest entry and rolls memory back to a snapshot in between.
+///
+/// `check` turns crashes into panics, which is what libFuzzer records; the
+/// `guest` target in `fuzz/` drives a harness this way under `cargo fuzz`.
+pub struct FuzzHarness<M: GuestMemory> {
+    backend: Box<dyn Backend>,
+    memory: M,
+    snapshot: Snapshot,
+    input: FuzzInput,
+    tracker: Option<DirtyTracker>,
+    on_exit: ExitHandler,
+    iterations: u64,
+    crashes: u64,
+}
+
+impl<M: GuestMemory> FuzzHarness<M> {
+    pub fn new(backend: Box<dyn Backend>, memory: M, snapshot: Snapshot, input: FuzzInput) -> Self {
+        Self {
+            backend,
+            memory,
+            snapshot,
+            input,
+            tracker: None,
+            on_exit: Box::new(default_exit_handler),
+            iterations: 0,
+            crashes: 0,
+        }
+    }
+
+    /// Only restore pages the guest wrote instead of the whole snapshot.
+    pub fn with_dirty_tracking(mut self, tracker: DirtyTracker) -> Self {
+        tracker.collect(true);
+        self.tracker = Some(tracker);
+        self
+    }
+
+    pub fn on_exit<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&mut DuneConfig) -> ExitAction + Send + 'static,
+    {
+        self.on_exit = Box::new(f);
+        self
+    }
+
+    pub fn iterations(&self) -> u64 {
+        self.iterations
+    }
+
+    pub fn crashes(&self) -> u64 {
+        self.crashes
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    fn outcome(conf: &DuneConfig) -> FuzzOutcome {
+        let ret = DuneRetCode::from(conf.ret());
+        let crashed = match ret {
+            DuneRetCode::Exit => conf.status() != 0,
+            DuneRetCode::EptViolation
+            | DuneRetCode::NoEnter
+            | DuneRetCode::UnhandledVmexit
+            | DuneRetCode::Unknown => true,
+            _ => false,
+        };
+        if crashed {
+            FuzzOutcome::Crash {
+                ret,
+                status: conf.status(),
+            }
+        } else {
+            FuzzOutcome::Ok
+        }
+    }
+
+    /// Run the guest on `data`, then reset it to the snapshot.
+    pub fn run(&mut self, data: &[u8]) -> Result<FuzzOutcome> {
+        let data = &data[..data.len().min(self.input.max_len)];
+        self.memory.write(self.input.addr, data)?;
+
+        let mut conf = self.snapshot.config().clone();
+        conf.set_rdi(self.input.addr).set_rsi(data.len() as u64);
+        let result = loop {
+            if let Err(err) = self.backend.enter(&mut conf) {
+                break Err(err);
+            }
+            if (self.on_exit)(&mut conf) == ExitAction::Stop {
+                break Ok(Self::outcome(&conf));
+            }
+        };
+
+        self.iterations += 1;
+        if let Ok(FuzzOutcome::Crash { .. }) = result {
+            self.crashes += 1;
+        }
+        self.reset()?;
+        result
+    }
+
+    /// Like `run`, but panics on a crash so fuzzing engines record it.
+    pub fn check(&mut self, data: &[u8]) {
+        match self.run(data) {
+            Ok(FuzzOutcome::Ok) => {}
+            Ok(FuzzOutcome::Crash { ret, status }) => {
+                panic!("guest crashed: {} (status {})", ret.name(), status)
+            }
+            Err(err) => panic!("fuzz harness failed: {}", err),
+        }
+    }
+
+    /// Roll guest memory back to the snapshot and return the number of pages restored.
+    pub fn reset(&mut self) -> Result<usize> {
+        let Some(tracker) = &self.tracker else {
+            self.snapshot.restore(&mut self.memory)?;
+            return Ok(self.snapshot.num_pages());
+        };
+
+        let zero = vec![0; PAGE_SIZE as usize];
+        let mut restored = 0;
+        for dirty in tracker.collect(true) {
+            for va in (dirty.va..dirty.va + dirty.size).step_by(PAGE_SIZE as usize) {
+                let page = self.snapshot.page(va).unwrap_or(&zero);
+                self.memory.write(va, page)?;
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::dune::DUNE_RET_EXIT;
+    use crate::mem::BufferMemory;
+
+    #[test]
+    fn tracker_finds_and_clears_dirty_pages() {
+        let mut tables: Vec<Box<PageTable>> = (0..4).map(|_| Box::new(PageTable::new())).collect();
+        let pa: Vec<PhysAddr> = tables.iter().map(|t| PhysAddr::new(&**t as *const _ as u64)).collect();
+        let link = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        tables[0][1].set_addr(pa[1], link);
+        tables[1][2].set_addr(pa[2], link);
+        tables[2][3].set_addr(pa[3], link);
+        tables[3][4].set_addr(PhysAddr::new(0x5000), link | PageTableFlags::DIRTY);
+        tables[3][5].set_addr(PhysAddr::new(0x6000), link);
+
+        let tracker = unsafe { DirtyTracker::new(pa[0].as_u64(), |pa| Some(VirtAddr::new(pa.as_u64()))) };
+        let va = (1 << 39) | (2 << 30) | (3 << 21) | (4 << 12);
+        assert_eq!(tracker.collect(true), vec![DirtyPage { va, size: PAGE_SIZE }]);
+        assert!(tracker.collect(false).is_empty());
+    }
+
+    #[test]
+    fn harness_resets_between_runs() {
+        let mut memory = BufferMemory::new(0x10000, PAGE_SIZE as usize);
+        memory.write(0x10000, b"seed").unwrap();
+        let snapshot = {
+            let mut snapshot = Snapshot::new(&DuneConfig::default());
+            snapshot.capture_range(&memory, 0x10000, PAGE_SIZE).unwrap();
+            snapshot
+        };
+
+        let backend = MockBackend::new();
+        backend.on_enter(|conf| {
+            let status = if conf.rsi() > 3 { 1 } else { 0 };
+            conf.set_ret(DUNE_RET_EXIT).set_status(status);
+        });
+        let input = FuzzInput {
+            addr: 0x10000,
+            max_len: 16,
+        };
+        let mut harness = FuzzHarness::new(Box::new(backend), memory, snapshot, input);
+
+        assert_eq!(harness.run(b"ok").unwrap(), FuzzOutcome::Ok);
+        assert_eq!(
+            harness.run(b"boom").unwrap(),
+            FuzzOutcome::Crash {
+                ret: DuneRetCode::Exit,
+                status: 1
+            }
+        );
+        assert_eq!(&harness.memory().as_slice()[..4], b"seed");
+        assert_eq!((harness.iterations(), harness.crashes()), (2, 1));
+    }
+}
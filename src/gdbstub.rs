@@ -0,0 +1,690 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::debug::DuneTrapRegs;
+use crate::dev::Device;
+use crate::mem::{GuestMemory, HostMemory};
+use crate::tracepoint::{Tracepoint, TracepointGuard};
+use crate::tracer::RFLAGS_TF;
+use crate::{Error, Result};
+
+pub const GDB_SIGTRAP: u8 = 5;
+
+/* rax..r15 and rip are 64 bits wide, eflags and the segment selectors 32 */
+const GDB_NUM_GPRS: usize = 17;
+const GDB_REG_EFLAGS: usize = 17;
+const GDB_NUM_REGS: usize = 24;
+
+const MAX_PACKET_SIZE: usize = 0x4000;
+
+/// What the stub needs from a stopped guest.
+pub trait GdbTarget {
+    fn registers(&mut self) -> Result<DuneTrapRegs>;
+    fn set_registers(&mut self, regs: &DuneTrapRegs) -> Result<()>;
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<()>;
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<()>;
+    fn add_breakpoint(&mut self, addr: u64) -> Result<()>;
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<()>;
+}
+
+/// How gdb asked the guest to proceed once a stop has been handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GdbAction {
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+fn gdb_reg(regs: &DuneTrapRegs, n: usize) -> Option<u64> {
+    let value = match n {
+        0 => regs.rax(),
+        1 => regs.rbx(),
+        2 => regs.rcx(),
+        3 => regs.rdx(),
+        4 => regs.rsi(),
+        5 => regs.rdi(),
+        6 => regs.rbp(),
+        7 => regs.rsp(),
+        8 => regs.r8(),
+        9 => regs.r9(),
+        10 => regs.r10(),
+        11 => regs.r11(),
+        12 => regs.r12(),
+        13 => regs.r13(),
+        14 => regs.r14(),
+        15 => regs.r15(),
+        16 => regs.rip(),
+        GDB_REG_EFLAGS => regs.rflags(),
+        /* cs, ss, ds, es, fs, gs are not captured by the trap */
+        18..GDB_NUM_REGS => 0,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn set_gdb_reg(regs: &mut DuneTrapRegs, n: usize, value: u64) -> bool {
+    match n {
+        0 => regs.set_rax(value),
+        1 => regs.set_rbx(value),
+        2 => regs.set_rcx(value),
+        3 => regs.set_rdx(value),
+        4 => regs.set_rsi(value),
+        5 => regs.set_rdi(value),
+        6 => regs.set_rbp(value),
+        7 => regs.set_rsp(value),
+        8 => regs.set_r8(value),
+        9 => regs.set_r9(value),
+        10 => regs.set_r10(value),
+        11 => regs.set_r11(value),
+        12 => regs.set_r12(value),
+        13 => regs.set_r13(value),
+        14 => regs.set_r14(value),
+        15 => regs.set_r15(value),
+        16 => regs.set_rip(value),
+        GDB_REG_EFLAGS => regs.set_rflags(value),
+        18..GDB_NUM_REGS => regs,
+        _ => return false,
+    };
+    true
+}
+
+fn gdb_reg_size(n: usize) -> usize {
+    if n < GDB_NUM_GPRS {
+        8
+    } else {
+        4
+    }
+}
+
+fn push_hex_le(out: &mut String, value: u64, size: usize) {
+    for byte in &value.to_le_bytes()[..size] {
+        out.push_str(&format!("{:02x}", byte));
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_hex_le(hex: &str) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn parse_addr_len(args: &str) -> Option<(u64, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// GDB remote serial protocol server speaking over any byte stream.
+pub struct GdbStub<S> {
+    stream: S,
+    no_ack: bool,
+    running: bool,
+}
+
+impl<S: Read + Write> GdbStub<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            no_ack: false,
+            running: false,
+        }
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_packet(&mut self) -> Result<String> {
+        loop {
+            /* skip acks, interrupts and noise until a packet starts */
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte if data.len() < MAX_PACKET_SIZE => data.push(byte),
+                    _ => return Err(Error::InvalidInput("GDB packet too large".to_string())),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected == Some(checksum(&data)) || self.no_ack {
+                if !self.no_ack {
+                    self.stream.write_all(b"+")?;
+                }
+                return String::from_utf8(data)
+                    .map_err(|_| Error::InvalidInput("GDB packet is not UTF-8".to_string()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<()> {
+        loop {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Report a stop to gdb if it is waiting for one, then serve commands
+    /// until it resumes, detaches or kills the guest.
+    pub fn handle_stop<T: GdbTarget>(&mut self, target: &mut T, signal: u8) -> Result<GdbAction> {
+        if self.running {
+            self.running = false;
+            self.write_packet(&format!("S{:02x}", signal))?;
+        }
+
+        loop {
+            let packet = self.read_packet()?;
+            let (reply, action) = self.dispatch(target, &packet, signal);
+            if let Some(reply) = reply {
+                self.write_packet(&reply)?;
+            }
+            if let Some(action) = action {
+                if matches!(action, GdbAction::Continue | GdbAction::Step) {
+                    self.running = true;
+                }
+                return Ok(action);
+            }
+        }
+    }
+
+    fn dispatch<T: GdbTarget>(
+        &mut self,
+        target: &mut T,
+        packet: &str,
+        signal: u8,
+    ) -> (Option<String>, Option<GdbAction>) {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match cmd {
+            "?" => format!("S{:02x}", signal),
+            "g" => self.read_registers(target),
+            "G" => self.write_registers(target, args),
+            "p" => self.read_register(target, args),
+            "P" => self.write_register(target, args),
+            "m" => self.read_memory(target, args),
+            "M" => self.write_memory(target, args),
+            "Z" | "z" => self.breakpoint(target, cmd == "Z", args),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let resumed = u64::from_str_radix(args, 16).ok().and_then(|rip| {
+                        let mut regs = target.registers().ok()?;
+                        regs.set_rip(rip);
+                        target.set_registers(&regs).ok()
+                    });
+                    if resumed.is_none() {
+                        return (Some("E01".to_string()), None);
+                    }
+                }
+                let action = if cmd == "c" {
+                    GdbAction::Continue
+                } else {
+                    GdbAction::Step
+                };
+                return (None, Some(action));
+            }
+            "D" => return (Some("OK".to_string()), Some(GdbAction::Detach)),
+            "k" => return (None, Some(GdbAction::Kill)),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        (Some(reply), None)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            /*
+             * PacketSize also bounds our replies: `m` reads are cut to
+             * MAX_PACKET_SIZE / 2 bytes, two hex digits each. The protocol
+             * allows short reads and gdb asks again for the rest.
+             */
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={:x};QStartNoAckMode+;swbreak+", MAX_PACKET_SIZE)
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn read_registers<T: GdbTarget>(&mut self, target: &mut T) -> String {
+        let regs = match target.registers() {
+            Ok(regs) => regs,
+            Err(_) => return "E01".to_string(),
+        };
+        let mut out = String::new();
+        for n in 0..GDB_NUM_REGS {
+            push_hex_le(&mut out, gdb_reg(&regs, n).unwrap_or(0), gdb_reg_size(n));
+        }
+        out
+    }
+
+    fn write_registers<T: GdbTarget>(&mut self, target: &mut T, args: &str) -> String {
+        let mut regs = match target.registers() {
+            Ok(regs) => regs,
+            Err(_) => return "E01".to_string(),
+        };
+        let mut offset = 0;
+        for n in 0..GDB_NUM_REGS {
+            let width = gdb_reg_size(n) * 2;
+            let Some(hex) = args.get(offset..offset + width) else {
+                break;
+            };
+            match decode_hex_le(hex) {
+                Some(value) => set_gdb_reg(&mut regs, n, value),
+                None => return "E02".to_string(),
+            };
+            offset += width;
+        }
+        match target.set_registers(&regs) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    fn read_register<T: GdbTarget>(&mut self, target: &mut T, args: &str) -> String {
+        let Ok(n) = usize::from_str_radix(args, 16) else {
+            return "E02".to_string();
+        };
+        let value = target.registers().ok().and_then(|regs| gdb_reg(&regs, n));
+        match value {
+            Some(value) => {
+                let mut out = String::new();
+                push_hex_le(&mut out, value, gdb_reg_size(n));
+                out
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register<T: GdbTarget>(&mut self, target: &mut T, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(n, value)| {
+            Some((usize::from_str_radix(n, 16).ok()?, decode_hex_le(value)?))
+        });
+        let Some((n, value)) = parsed else {
+            return "E02".to_string();
+        };
+        let updated = target.registers().ok().and_then(|mut regs| {
+            if !set_gdb_reg(&mut regs, n, value) {
+                return None;
+            }
+            target.set_registers(&regs).ok()
+        });
+        match updated {
+            Some(()) => "OK".to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_memory<T: GdbTarget>(&mut self, target: &mut T, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return "E02".to_string();
+        };
+        let mut buf = vec![0; len.min(MAX_PACKET_SIZE / 2)];
+        match target.read_memory(addr, &mut buf) {
+            Ok(()) => buf.iter().map(|b| format!("{:02x}", b)).collect(),
+            Err(_) => "E14".to_string(),
+        }
+    }
+
+    fn write_memory<T: GdbTarget>(&mut self, target: &mut T, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = parse_addr_len(range)?;
+            let data = decode_hex(data)?;
+            (data.len() == len).then_some((addr, data))
+        });
+        let Some((addr, data)) = parsed else {
+            return "E02".to_string();
+        };
+        match target.write_memory(addr, &data) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E14".to_string(),
+        }
+    }
+
+    fn breakpoint<T: GdbTarget>(&mut self, target: &mut T, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|addr| u64::from_str_radix(addr, 16).ok());
+        /* only software breakpoints, which we implement with the trap facility */
+        let (Some("0"), Some(addr)) = (kind, addr) else {
+            return String::new();
+        };
+        let ret = if insert {
+            target.add_breakpoint(addr)
+        } else {
+            target.remove_breakpoint(addr)
+        };
+        match ret {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+}
+
+/// Wait for a single gdb connection on a TCP address.
+pub fn accept_tcp<A: ToSocketAddrs>(addr: A) -> Result<GdbStub<TcpStream>> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(GdbStub::new(stream))
+}
+
+/// Wait for a single gdb connection on a unix socket.
+pub fn accept_unix<P: AsRef<Path>>(path: P) -> Result<GdbStub<UnixStream>> {
+    let listener = UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    Ok(GdbStub::new(stream))
+}
+
+struct TrapTarget<'a, M: GuestMemory> {
+    regs: &'a mut DuneTrapRegs,
+    memory: M,
+    breakpoints: &'a mut BTreeSet<u64>,
+}
+
+impl<M: GuestMemory> GdbTarget for TrapTarget<'_, M> {
+    fn registers(&mut self) -> Result<DuneTrapRegs> {
+        Ok(*self.regs)
+    }
+
+    fn set_registers(&mut self, regs: &DuneTrapRegs) -> Result<()> {
+        *self.regs = *regs;
+        Ok(())
+    }
+
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.memory.read(addr, buf)
+    }
+
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        self.memory.write(addr, data)
+    }
+
+    fn add_breakpoint(&mut self, addr: u64) -> Result<()> {
+        self.breakpoints.insert(addr);
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<()> {
+        self.breakpoints.remove(&addr);
+        Ok(())
+    }
+}
+
+/// Debugs code running in guest mode from inside the trap handler.
+///
+/// The guest stops at `entry_rip` and gdb takes over. The trap facility
+/// only watches a single RIP, so while breakpoints are set the guest is
+/// single-stepped and stops are reported when RIP reaches one of them.
+///
+/// A kill request from gdb ends the session like a detach; `killed` then
+/// reports it and the caller decides what happens to the guest.
+pub struct GuestDebugger {
+    tracepoint: Tracepoint,
+    killed: Arc<AtomicBool>,
+}
+
+impl GuestDebugger {
+    pub fn new<S>(entry_rip: u64, stream: S) -> Self
+    where
+        S: Read + Write + Send + 'static,
+    {
+        let mut stub = GdbStub::new(stream);
+        let mut breakpoints = BTreeSet::new();
+        let mut stepping = true;
+        let mut detached = false;
+        let killed = Arc::new(AtomicBool::new(false));
+        let kill = killed.clone();
+
+        let tracepoint = Tracepoint::at(entry_rip, move |regs| {
+            if !detached && (stepping || breakpoints.contains(&regs.rip())) {
+                let mut target = TrapTarget {
+                    regs: &mut *regs,
+                    memory: HostMemory::new(),
+                    breakpoints: &mut breakpoints,
+                };
+                match stub.handle_stop(&mut target, GDB_SIGTRAP) {
+                    Ok(GdbAction::Step) => stepping = true,
+                    Ok(GdbAction::Continue) => stepping = false,
+                    Ok(GdbAction::Detach) | Err(_) => detached = true,
+                    Ok(GdbAction::Kill) => {
+                        kill.store(true, Ordering::Release);
+                        detached = true;
+                    }
+                }
+            }
+
+            if !detached && (stepping || !breakpoints.is_empty()) {
+                regs.set_rflags(regs.rflags() | RFLAGS_TF);
+            } else {
+                regs.set_rflags(regs.rflags() & !RFLAGS_TF);
+            }
+        });
+
+        Self { tracepoint, killed }
+    }
+
+    /// Whether gdb asked to kill the guest.
+    pub fn killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    pub fn enable<D: Device>(&mut self, device: &D) -> Result<TracepointGuard<'_>> {
+        self.tracepoint.enable(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+    use std::io::Cursor;
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packets(cmds: &[&str]) -> Vec<u8> {
+        cmds.iter()
+            .flat_map(|cmd| format!("${}#{:02x}+", cmd, checksum(cmd.as_bytes())).into_bytes())
+            .collect()
+    }
+
+    fn replies(output: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(output)
+            .split('$')
+            .skip(1)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn session_reads_registers_and_memory() {
+        let mut regs = DuneTrapRegs::default();
+        regs.set_rax(0x1122).set_rip(0x401000);
+        let mut memory = BufferMemory::new(0x1000, 16);
+        memory.write(0x1000, &[0xde, 0xad]).unwrap();
+        let mut breakpoints = BTreeSet::new();
+        let mut target = TrapTarget {
+            regs: &mut regs,
+            memory,
+            breakpoints: &mut breakpoints,
+        };
+
+        let stream = Duplex {
+            input: Cursor::new(packets(&[
+                "?",
+                "p10",
+                "m1000,2",
+                "M1002,1:ff",
+                "Z0,401010,1",
+                "P0=3300000000000000",
+                "s",
+            ])),
+            output: Vec::new(),
+        };
+        let mut stub = GdbStub::new(stream);
+        let action = stub.handle_stop(&mut target, GDB_SIGTRAP).unwrap();
+
+        assert_eq!(action, GdbAction::Step);
+        assert_eq!(
+            replies(&stub.into_inner().output),
+            vec!["S05", "0010400000000000", "dead", "OK", "OK", "OK"]
+        );
+        assert_eq!(target.memory.as_slice()[2], 0xff);
+        assert!(target.breakpoints.contains(&0x401010));
+        assert_eq!(target.regs.rax(), 0x33);
+    }
+
+    #[test]
+    fn register_packet_roundtrip() {
+        let mut regs = DuneTrapRegs::default();
+        regs.set_rsp(0x7fff0000).set_rflags(0x246);
+        let mut breakpoints = BTreeSet::new();
+        let mut target = TrapTarget {
+            regs: &mut regs,
+            memory: BufferMemory::default(),
+            breakpoints: &mut breakpoints,
+        };
+        let mut stub = GdbStub::new(Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        });
+
+        let g = stub.read_registers(&mut target);
+        assert_eq!(g.len(), GDB_NUM_GPRS * 16 + (GDB_NUM_REGS - GDB_NUM_GPRS) * 8);
+        target.regs.set_rsp(0).set_rflags(0);
+        assert_eq!(stub.write_registers(&mut target, &g), "OK");
+        assert_eq!(target.regs.rsp(), 0x7fff0000);
+        assert_eq!(target.regs.rflags(), 0x246);
+    }
+
+    /// Drives the stub with a real `gdb -batch` over a unix socket.
+    #[test]
+    #[ignore = "needs gdb; run with --ignored"]
+    fn gdb_batch_session() {
+        let path = std::env::temp_dir().join(format!("dune-gdbstub-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let socket = path.clone();
+        let server = std::thread::spawn(move || {
+            let mut regs = DuneTrapRegs::default();
+            regs.set_rip(0x401000).set_rsp(0x7fff0000);
+            let mut memory = BufferMemory::new(0x1000, 16);
+            memory.write(0x1000, &[0xde, 0xad]).unwrap();
+            let mut breakpoints = BTreeSet::new();
+            let mut target = TrapTarget {
+                regs: &mut regs,
+                memory,
+                breakpoints: &mut breakpoints,
+            };
+            let mut stub = accept_unix(&socket).unwrap();
+            let action = stub.handle_stop(&mut target, GDB_SIGTRAP).unwrap();
+            (action, target.regs.rax())
+        });
+        while !path.exists() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let output = std::process::Command::new("gdb")
+            .args(["-nx", "-batch", "-ex", "set architecture i386:x86-64"])
+            .args(["-ex", &format!("target remote {}", path.display())])
+            .args(["-ex", "p/x $rip", "-ex", "x/2xb 0x1000", "-ex", "set var $rax = 0x33"])
+            .args(["-ex", "kill"])
+            .output()
+            .expect("failed to run gdb");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let _ = std::fs::remove_file(&path);
+        for _ in 0..500 {
+            if server.is_finished() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(
+            server.is_finished(),
+            "gdb did not finish the session:\n{}{}",
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        assert_eq!(server.join().unwrap(), (GdbAction::Kill, 0x33));
+        assert!(stdout.contains("0x401000"), "{}", stdout);
+        assert!(stdout.contains("0xde\t0xad"), "{}", stdout);
+    }
+}
@@ -42,6 +42,15 @@ impl IdtDescriptor {
         self.high = ((addr >> 32) & 0xFFFFFFFF) as u32;
         self
     }
+
+    pub fn idt_addr(&self) -> usize {
+        self.low as usize | (self.middle as usize) << 16 | (self.high as usize) << 32
+    }
+
+    /// Whether the gate has its present bit set.
+    pub fn is_present(&self) -> bool {
+        self.type_attr & 0x80 != 0
+    }
 }
 
 impl AsRef<[u8]> for IdtDescriptor {
@@ -0,0 +1,83 @@
+//! Structured diagnostics, compiled in only with the `tracing` feature.
+//!
+//! The macros here forward to `tracing` when the feature is on and expand to
+//! nothing otherwise, so instrumented paths cost nothing in default builds.
+
+use crate::dune::DuneConfig;
+#[cfg(feature = "tracing")]
+use crate::dune::DuneRetCode;
+
+/// `trace_event!(LEVEL, fields..., "message")` with `tracing::event!` syntax.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        tracing::event!(tracing::Level::$level, $($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {};
+}
+
+/// Log the outcome of an ioctl; the trailing fields summarize its payload.
+#[cfg(feature = "tracing")]
+macro_rules! ioctl_event {
+    ($name:expr, $fd:expr, $result:expr $(, $($field:tt)+)?) => {
+        match &$result {
+            Ok(_) => $crate::instrument::trace_event!(
+                TRACE, ioctl = $name, fd = $fd $(, $($field)+)?, "ioctl"
+            ),
+            Err(errno) => $crate::instrument::trace_event!(
+                WARN, ioctl = $name, fd = $fd, %errno $(, $($field)+)?, "ioctl failed"
+            ),
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! ioctl_event {
+    ($name:expr, $fd:expr, $result:expr $(, $($field:tt)+)?) => {
+        let _ = &$result;
+    };
+}
+
+pub(crate) use {ioctl_event, trace_event};
+
+/// Log why the guest exited.
+#[cfg(feature = "tracing")]
+pub(crate) fn exit_event(config: &DuneConfig) {
+    tracing::debug!(
+        ret = DuneRetCode::from(config.ret()).name(),
+        status = config.status(),
+        rip = config.rip(),
+        "guest exit"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn exit_event(_config: &DuneConfig) {}
+
+/// Keeps a per-vCPU span entered while a guest runs.
+///
+/// Events logged on the vCPU thread inside it carry the vCPU id, which is
+/// what ties guest exits to the rest of the host logs.
+#[must_use]
+pub struct VcpuSpan {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+pub fn vcpu_span(vcpu: u32) -> VcpuSpan {
+    #[cfg(feature = "tracing")]
+    {
+        VcpuSpan {
+            _span: tracing::debug_span!("vcpu", id = vcpu).entered(),
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = vcpu;
+        VcpuSpan {}
+    }
+}
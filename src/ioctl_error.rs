@@ -0,0 +1,151 @@
+use std::fmt;
+
+use nix::errno::Errno;
+
+use crate::Error;
+
+/// Define an error enum for one ioctl, mapping each errno it documents to a
+/// variant. Anything else lands in `Other`. Each variant's message doubles
+/// as its doc comment.
+macro_rules! ioctl_error {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident($op:literal) {
+            $($variant:ident = $errno:ident => $msg:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum $name {
+            $(#[doc = $msg] $variant,)*
+            /// An errno this ioctl is not documented to return.
+            Other(Errno),
+        }
+
+        impl $name {
+            /// Name of the ioctl these errors come from.
+            pub const OP: &'static str = $op;
+
+            pub fn errno(&self) -> Errno {
+                match self {
+                    $($name::$variant => Errno::$errno,)*
+                    $name::Other(errno) => *errno,
+                }
+            }
+        }
+
+        impl From<Errno> for $name {
+            fn from(errno: Errno) -> Self {
+                match errno {
+                    $(Errno::$errno => $name::$variant,)*
+                    other => $name::Other(other),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $($name::$variant => write!(f, "{}: {}", $op, $msg),)*
+                    $name::Other(errno) => write!(f, "{} failed: {}", $op, errno),
+                }
+            }
+        }
+
+        impl std::error::Error for $name {}
+
+        impl From<$name> for Error {
+            fn from(err: $name) -> Self {
+                Error::Ioctl {
+                    name: $op,
+                    errno: err.errno(),
+                }
+            }
+        }
+    };
+}
+
+ioctl_error! {
+    /// Failures of `DUNE_ENTER`.
+    pub enum EnterError("DUNE_ENTER") {
+        BadConfig = EFAULT => "the DuneConfig pointer is not accessible",
+        InvalidConfig = EINVAL => "the DuneConfig contents were rejected",
+        OutOfMemory = ENOMEM => "the vCPU or its EPT could not be allocated",
+        Busy = EBUSY => "VT-x is unavailable or already in use on this CPU",
+        Interrupted = EINTR => "a signal arrived before the guest was entered",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `DUNE_GET_LAYOUT`.
+    pub enum LayoutError("DUNE_GET_LAYOUT") {
+        BadLayout = EFAULT => "the DuneLayout pointer is not accessible",
+        NotSupported = ENOTTY => "the Dune module does not report its layout",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `DUNE_TRAP_ENABLE`.
+    pub enum TrapEnableError("DUNE_TRAP_ENABLE") {
+        BadConfig = EFAULT => "the DuneTrapConfig pointer is not accessible",
+        InvalidConfig = EINVAL => "the trigger address or register buffer was rejected",
+        AlreadyEnabled = EBUSY => "a trap is already armed on this device",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `VMPL_RUN`.
+    pub enum VmplRunError("VMPL_RUN") {
+        BadConfig = EFAULT => "the DuneConfig pointer is not accessible",
+        InvalidConfig = EINVAL => "the DuneConfig contents were rejected",
+        OutOfMemory = ENOMEM => "the VMSA or vCPU state could not be allocated",
+        NoVm = ENODEV => "no VM has been created on this device",
+        Interrupted = EINTR => "a signal arrived before the guest was entered",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `VMPL_GET_LAYOUT`.
+    pub enum VmplLayoutError("VMPL_GET_LAYOUT") {
+        BadLayout = EFAULT => "the VmplLayout pointer is not accessible",
+        NotSupported = ENOTTY => "the VMPL module does not report its layout",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `VMPL_SET_PAGE_VMPL`.
+    pub enum PageVmplError("VMPL_SET_PAGE_VMPL") {
+        BadAddress = EFAULT => "the guest virtual address is not mapped",
+        InvalidArgs = EINVAL => "the page size, attributes or page count were rejected",
+        NotOwned = EPERM => "the page is not assigned to this guest",
+        OutOfMemory = ENOMEM => "the RMP update could not allocate memory",
+    }
+}
+
+ioctl_error! {
+    /// Failures of `VMPL_GET_PAGES`.
+    pub enum GetPagesError("VMPL_GET_PAGES") {
+        BadPages = EFAULT => "the GetPages pointer is not accessible",
+        InvalidCount = EINVAL => "the page count was zero or too large",
+        OutOfMemory = ENOMEM => "not enough guest pages are free",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_errno_differs_per_ioctl() {
+        assert_eq!(EnterError::from(Errno::EFAULT), EnterError::BadConfig);
+        assert_eq!(PageVmplError::from(Errno::EFAULT), PageVmplError::BadAddress);
+        assert_eq!(EnterError::from(Errno::EIO), EnterError::Other(Errno::EIO));
+        assert_eq!(
+            PageVmplError::BadAddress.to_string(),
+            "VMPL_SET_PAGE_VMPL: the guest virtual address is not mapped"
+        );
+
+        let err: Error = PageVmplError::NotOwned.into();
+        assert!(matches!(err, Error::Ioctl { name: "VMPL_SET_PAGE_VMPL", errno: Errno::EPERM }));
+    }
+}
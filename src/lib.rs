@@ -13,6 +13,32 @@ pub mod dev;
 pub mod idt;
 #[macro_use]
 pub mod tss;
+#[macro_use]
+pub mod vmsa;
+pub mod probe;
+pub mod backend;
+pub mod tracepoint;
+pub mod tracer;
+pub mod mem;
+pub mod gdbstub;
+pub mod regs;
+pub mod dump;
+pub mod crash;
+pub mod elf;
+pub mod coredump;
+pub mod snapshot;
+pub mod fuzz;
+pub mod signal;
+pub mod timer;
+pub mod sched;
+pub mod ioctl_error;
+pub mod stack;
+pub mod loader;
+pub mod vdso;
+pub mod stats;
+pub mod instrument;
+pub mod ioctl;
+pub mod abi;
 
 pub use crate::result::*;
 pub use crate::trap::*;
@@ -22,6 +48,30 @@ pub use crate::vmpl::*;
 pub use crate::dev::*;
 pub use crate::idt::*;
 pub use crate::tss::*;
+pub use crate::vmsa::*;
+pub use crate::probe::*;
+pub use crate::backend::*;
+pub use crate::tracepoint::*;
+pub use crate::tracer::*;
+pub use crate::mem::*;
+pub use crate::gdbstub::*;
+pub use crate::regs::*;
+pub use crate::dump::*;
+pub use crate::crash::*;
+pub use crate::coredump::*;
+pub use crate::snapshot::*;
+pub use crate::fuzz::*;
+pub use crate::signal::*;
+pub use crate::timer::*;
+pub use crate::sched::*;
+pub use crate::ioctl_error::*;
+pub use crate::stack::*;
+pub use crate::loader::*;
+pub use crate::vdso::*;
+pub use crate::stats::*;
+pub use crate::instrument::{vcpu_span, VcpuSpan};
+pub use crate::ioctl::{ioctl_name, IoctlDir, IoctlRequest};
+pub use crate::abi::*;
 
 /// Generate set/get methods for a given struct field and type
 
@@ -70,6 +120,22 @@ macro_rules! funcs_vec {
     };
 }
 
+/// Generate `pub const` byte offsets of struct fields, for `const` asm operands.
+///
+/// `offset_consts!(Type, PREFIX { field, .. })` names each one `PREFIX_FIELD`;
+/// `offset_consts!(Type { NAME = field, .. })` names them explicitly.
+#[macro_export]
+macro_rules! offset_consts {
+    ($T: ty { $($name: ident = $field: ident),* $(,)? }) => {
+        $(pub const $name: usize = ::std::mem::offset_of!($T, $field);)*
+    };
+    ($T: ty, $prefix: ident { $($field: ident),* $(,)? }) => {
+        paste::paste! {
+            $crate::offset_consts!($T { $([<$prefix _ $field:upper>] = $field),* });
+        }
+    };
+}
+
 #[macro_export]
 #[allow(unused_macros)]
 macro_rules! lg_align {
@@ -91,4 +157,31 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    std::arch::global_asm!(
+        ".globl offset_consts_load_rip",
+        "offset_consts_load_rip:",
+        "mov rax, [rdi + {tf_rip}]",
+        "add rax, [rsi + {ist}]",
+        "ret",
+        tf_rip = const DUNE_TF_RIP,
+        ist = const TSS_IST + 8,
+    );
+
+    extern "C" {
+        fn offset_consts_load_rip(tf: *const DuneTf, tss: *const Tss) -> u64;
+    }
+
+    #[test]
+    fn offset_consts_in_asm() {
+        assert_eq!((DUNE_CFG_RIP, DUNE_TF_RIP, DUNE_TRAP_REGS_RFLAGS), (136, 128, 136));
+        assert_eq!((TSS_RSP, TSS_IST, TSS_IOPB), (4, 36, 104));
+        assert_eq!((VCPU_CONFIG_LSTAR, VMSA_SEG_BASE, GET_PAGES_PHYS), (80, 8, 16));
+
+        let mut tf = DuneTf::default();
+        tf.set_rip(0x1000);
+        let mut tss = Tss::default();
+        tss.set_tss_ist(1, 0x234);
+        assert_eq!(unsafe { offset_consts_load_rip(&tf, &tss) }, 0x1234);
+    }
 }
@@ -0,0 +1,590 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::path::Path;
+
+use nix::errno::Errno;
+
+use crate::backend::{
+    prot_from_perm, Backend, PAGE_PERM_EXEC_USER, PAGE_PERM_READ, PAGE_PERM_WRITE, PAGE_SIZE,
+};
+use crate::dune::{DuneConfig, DuneLayout};
+use crate::elf::{
+    ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_X86_64, ET_DYN, ET_EXEC, PF_R, PF_W, PF_X, PT_LOAD, PT_PHDR,
+};
+use crate::mem::{read_pod, BufferMemory, GuestMemory, HostMemory};
+use crate::snapshot::page_runs;
+use crate::stack::StackBuilder;
+use crate::{Error, Result};
+
+pub const DEFAULT_GUEST_STACK_SIZE: u64 = 8 << 20;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Elf64Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+fn page_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn perm_from_flags(flags: u32) -> u32 {
+    let mut perm = 0;
+    if flags & PF_R != 0 {
+        perm |= PAGE_PERM_READ;
+    }
+    if flags & PF_W != 0 {
+        perm |= PAGE_PERM_WRITE;
+    }
+    if flags & PF_X != 0 {
+        perm |= PAGE_PERM_EXEC_USER;
+    }
+    perm
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidInput(format!("ELF: {}", msg))
+}
+
+/// Creates guest mappings for the loader.
+///
+/// `map` must leave the range zero-filled and writable so the loader can copy
+/// segments in; `protect` then applies the final `PAGE_PERM_*` bits.
+pub trait Mapper: GuestMemory {
+    fn map(&mut self, addr: u64, len: u64) -> Result<()>;
+    fn protect(&mut self, addr: u64, len: u64, perm: u32) -> Result<()>;
+}
+
+/// Maps guest memory in this process, which is the guest address space under Dune.
+pub struct HostMapper<'a> {
+    backend: Option<&'a dyn Backend>,
+}
+
+impl<'a> HostMapper<'a> {
+    pub fn new() -> Self {
+        Self { backend: None }
+    }
+
+    /// Apply permissions through the backend instead of a plain `mprotect`.
+    pub fn with_backend(backend: &'a dyn Backend) -> Self {
+        Self {
+            backend: Some(backend),
+        }
+    }
+}
+
+impl Default for HostMapper<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestMemory for HostMapper<'_> {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        HostMemory.read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        HostMemory.write(addr, data)
+    }
+}
+
+impl Mapper for HostMapper<'_> {
+    fn map(&mut self, addr: u64, len: u64) -> Result<()> {
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut _,
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(Error::last_memory(addr));
+        }
+        if ret as u64 != addr {
+            /* kernels before 4.17 treat the flag as a hint */
+            unsafe { libc::munmap(ret, len as usize) };
+            return Err(Error::Memory {
+                addr,
+                errno: Errno::EEXIST,
+            });
+        }
+        Ok(())
+    }
+
+    fn protect(&mut self, addr: u64, len: u64, perm: u32) -> Result<()> {
+        if let Some(backend) = self.backend {
+            let va = x86_64::VirtAddr::new(addr);
+            return backend.set_page_perm(va, (len / PAGE_SIZE) as u32, perm);
+        }
+        if unsafe { libc::mprotect(addr as *mut _, len as usize, prot_from_perm(perm)) } < 0 {
+            return Err(Error::last_memory(addr));
+        }
+        Ok(())
+    }
+}
+
+/// A `BufferMemory` that records mappings, for loading without touching this process.
+#[derive(Debug, Clone)]
+pub struct BufferMapper {
+    memory: BufferMemory,
+    perms: BTreeMap<u64, u32>,
+}
+
+impl BufferMapper {
+    pub fn new(base: u64, len: usize) -> Self {
+        Self {
+            memory: BufferMemory::new(base, len),
+            perms: BTreeMap::new(),
+        }
+    }
+
+    pub fn memory(&self) -> &BufferMemory {
+        &self.memory
+    }
+
+    /// Permission of the page containing `addr`, if it was mapped.
+    pub fn perm(&self, addr: u64) -> Option<u32> {
+        self.perms.get(&page_down(addr)).copied()
+    }
+}
+
+impl GuestMemory for BufferMapper {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.memory.read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        self.memory.write(addr, data)
+    }
+}
+
+impl Mapper for BufferMapper {
+    fn map(&mut self, addr: u64, len: u64) -> Result<()> {
+        if addr < self.memory.base() || addr + len > self.memory.end() {
+            return Err(Error::InvalidAddress);
+        }
+        for page in (addr..addr + len).step_by(PAGE_SIZE as usize) {
+            if self.perms.insert(page, PAGE_PERM_READ | PAGE_PERM_WRITE).is_some() {
+                return Err(Error::AlreadyExists);
+            }
+        }
+        let start = (addr - self.memory.base()) as usize;
+        self.memory.as_mut_slice()[start..start + len as usize].fill(0);
+        Ok(())
+    }
+
+    fn protect(&mut self, addr: u64, len: u64, perm: u32) -> Result<()> {
+        for page in (addr..addr + len).step_by(PAGE_SIZE as usize) {
+            *self.perms.get_mut(&page).ok_or(Error::InvalidAddress)? = perm;
+        }
+        Ok(())
+    }
+}
+
+/// Where and how big the guest stack is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackConfig {
+    pub top: u64,
+    pub size: u64,
+}
+
+impl StackConfig {
+    /// A stack ending one guard page below the Dune stack region.
+    pub fn below_base_stack(layout: &DuneLayout) -> Result<Self> {
+        let top = layout
+            .base_stack()
+            .as_u64()
+            .checked_sub(PAGE_SIZE)
+            .ok_or_else(|| invalid("Dune stack region starts in the first page"))?;
+        Ok(Self {
+            top,
+            size: DEFAULT_GUEST_STACK_SIZE,
+        })
+    }
+
+    /// The page-aligned `(start, len)` the stack occupies.
+    fn pages(&self) -> Result<(u64, u64)> {
+        let bottom = self
+            .top
+            .checked_sub(self.size)
+            .map(page_down)
+            .ok_or_else(|| invalid("stack size exceeds its top address"))?;
+        let end = self
+            .top
+            .checked_add(PAGE_SIZE - 1)
+            .map(page_down)
+            .ok_or_else(|| invalid("stack top wraps around the address space"))?;
+        Ok((bottom, end - bottom))
+    }
+}
+
+/// Entry state of a loaded image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub entry: u64,
+    pub rsp: u64,
+    /// Guest address of the program headers, for `AT_PHDR`.
+    pub phdr: u64,
+    /// First page after the highest segment, where a heap can start.
+    pub brk: u64,
+}
+
+impl LoadedImage {
+    pub fn apply(&self, conf: &mut DuneConfig) {
+        conf.set_rip(self.entry).set_rsp(self.rsp);
+    }
+}
+
+/// A parsed static ELF64 x86-64 executable.
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+    data: Vec<u8>,
+    ehdr: Elf64Ehdr,
+    phdrs: Vec<Elf64Phdr>,
+    bias: u64,
+}
+
+impl ElfImage {
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let memory = {
+            let mut memory = BufferMemory::new(0, data.len());
+            memory.as_mut_slice().copy_from_slice(&data);
+            memory
+        };
+        let ehdr: Elf64Ehdr = read_pod(&memory, 0).map_err(|_| invalid("truncated header"))?;
+        if ehdr.e_ident[..4] != ELFMAG {
+            return Err(invalid("bad magic"));
+        }
+        if ehdr.e_ident[4] != ELFCLASS64 || ehdr.e_ident[5] != ELFDATA2LSB {
+            return Err(invalid("not a little-endian ELF64 file"));
+        }
+        if ehdr.e_machine != EM_X86_64 {
+            return Err(invalid("not an x86-64 executable"));
+        }
+        if ehdr.e_type != ET_EXEC && ehdr.e_type != ET_DYN {
+            return Err(invalid("not an executable"));
+        }
+        if ehdr.e_phentsize as usize != size_of::<Elf64Phdr>() {
+            return Err(invalid("unexpected program header size"));
+        }
+
+        let phdrs = (0..ehdr.e_phnum as u64)
+            .map(|i| read_pod(&memory, ehdr.e_phoff + i * size_of::<Elf64Phdr>() as u64))
+            .collect::<Result<Vec<Elf64Phdr>>>()
+            .map_err(|_| invalid("truncated program headers"))?;
+        for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+            if phdr.p_filesz > phdr.p_memsz {
+                return Err(invalid("segment file size exceeds memory size"));
+            }
+            if phdr.p_offset.checked_add(phdr.p_filesz).is_none_or(|end| end > data.len() as u64) {
+                return Err(invalid("segment extends past end of file"));
+            }
+        }
+
+        let image = Self {
+            data,
+            ehdr,
+            phdrs,
+            bias: 0,
+        };
+        image.check_addresses()?;
+        Ok(image)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Load a position-independent executable at `base`.
+    pub fn with_bias(mut self, base: u64) -> Result<Self> {
+        if self.ehdr.e_type != ET_DYN {
+            return Err(invalid("only ET_DYN images can be relocated"));
+        }
+        self.bias = base;
+        self.check_addresses()?;
+        Ok(self)
+    }
+
+    /// Reject images whose segments, program headers or entry point wrap
+    /// around the address space once relocated by `bias`.
+    fn check_addresses(&self) -> Result<()> {
+        let relocate = |vaddr: u64| vaddr.checked_add(self.bias);
+        for phdr in self.phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+            relocate(phdr.p_vaddr)
+                .and_then(|start| start.checked_add(phdr.p_memsz))
+                .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+                .ok_or_else(|| invalid("segment wraps around the address space"))?;
+        }
+        if let Some(phdr) = self.phdrs.iter().find(|phdr| phdr.p_type == PT_PHDR) {
+            relocate(phdr.p_vaddr).ok_or_else(|| invalid("program header address overflows"))?;
+        }
+        let phoff = self.ehdr.e_phoff;
+        let containing = |phdr: &&Elf64Phdr| phoff >= phdr.p_offset && phoff < phdr.p_offset + phdr.p_filesz;
+        for phdr in self.loads().filter(containing) {
+            phdr.p_vaddr
+                .checked_add(phoff - phdr.p_offset)
+                .and_then(relocate)
+                .ok_or_else(|| invalid("program header address overflows"))?;
+        }
+        relocate(self.ehdr.e_entry).ok_or_else(|| invalid("entry point overflows"))?;
+        Ok(())
+    }
+
+    pub fn header(&self) -> &Elf64Ehdr {
+        &self.ehdr
+    }
+
+    pub fn program_headers(&self) -> &[Elf64Phdr] {
+        &self.phdrs
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.ehdr.e_entry + self.bias
+    }
+
+    fn loads(&self) -> impl Iterator<Item = &Elf64Phdr> {
+        self.phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD && phdr.p_memsz > 0)
+    }
+
+    /// Guest address the program headers end up at.
+    pub fn phdr_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.phdrs.iter().find(|phdr| phdr.p_type == PT_PHDR) {
+            return Some(phdr.p_vaddr + self.bias);
+        }
+        let phoff = self.ehdr.e_phoff;
+        self.loads()
+            .find(|phdr| phoff >= phdr.p_offset && phoff < phdr.p_offset + phdr.p_filesz)
+            .map(|phdr| phdr.p_vaddr + (phoff - phdr.p_offset) + self.bias)
+    }
+
+    /// Map the PT_LOAD segments: copy their file bytes, zero BSS and apply permissions.
+    ///
+    /// Returns the page just past the highest segment.
+    pub fn load_segments(&self, mapper: &mut dyn Mapper) -> Result<u64> {
+        /* segments may share a page; it gets the union of their permissions */
+        let mut pages: BTreeMap<u64, u32> = BTreeMap::new();
+        for phdr in self.loads() {
+            let start = page_down(phdr.p_vaddr + self.bias);
+            let end = page_up(phdr.p_vaddr + self.bias + phdr.p_memsz);
+            for page in (start..end).step_by(PAGE_SIZE as usize) {
+                *pages.entry(page).or_default() |= perm_from_flags(phdr.p_flags);
+            }
+        }
+        let brk = pages.keys().next_back().map_or(0, |page| page + PAGE_SIZE);
+
+        for (start, len) in page_runs(pages.keys().copied()) {
+            mapper.map(start, len)?;
+        }
+        for phdr in self.loads() {
+            let vaddr = phdr.p_vaddr + self.bias;
+            let file = &self.data[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
+            /* the rest up to p_memsz is BSS, already zeroed by map */
+            mapper.write(vaddr, file)?;
+        }
+        for (page, perm) in pages {
+            mapper.protect(page, PAGE_SIZE, perm)?;
+        }
+        Ok(brk)
+    }
+
+    /// Load the image and build its initial stack.
+    pub fn load(
+        &self,
+        mapper: &mut dyn Mapper,
+        stack: StackConfig,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<LoadedImage> {
+        let (bottom, len) = stack.pages()?;
+        let brk = self.load_segments(mapper)?;
+        let phdr = self.phdr_addr().unwrap_or(0);
+
+        mapper.map(bottom, len)?;
+
+        let mut random = [0u8; 16];
+        if unsafe { libc::getrandom(random.as_mut_ptr() as *mut _, random.len(), 0) } < 0 {
+            return Err(Error::last_syscall("getrandom"));
+        }
+        let rsp = StackBuilder::new(stack.top)
+            .args(argv)
+            .envs(envp)
+            .phdr(phdr, size_of::<Elf64Phdr>() as u64, self.ehdr.e_phnum as u64)
+            .entry(self.entry())
+            .random(random)
+            .build()?
+            .write_to(mapper)?;
+        Ok(LoadedImage {
+            entry: self.entry(),
+            rsp,
+            phdr,
+            brk,
+        })
+    }
+}
+
+/// Contiguous runs of sorted page addresses as `(start, len)`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::offset_of;
+
+    fn pod_bytes<T>(value: &T) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+    }
+
+    /// A static ELF with a text segment and a data segment with BSS.
+    fn sample_elf() -> Vec<u8> {
+        let ehdr_size = size_of::<Elf64Ehdr>() as u64;
+        let phdr_size = size_of::<Elf64Phdr>() as u64;
+        let mut e_ident = [0; 16];
+        e_ident[..4].copy_from_slice(&ELFMAG);
+        e_ident[4] = ELFCLASS64;
+        e_ident[5] = ELFDATA2LSB;
+        e_ident[6] = 1;
+        let ehdr = Elf64Ehdr {
+            e_ident,
+            e_type: ET_EXEC,
+            e_machine: EM_X86_64,
+            e_version: 1,
+            e_entry: 0x401000,
+            e_phoff: ehdr_size,
+            e_ehsize: ehdr_size as u16,
+            e_phentsize: phdr_size as u16,
+            e_phnum: 2,
+            ..Default::default()
+        };
+        let text = Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_X,
+            p_offset: 0,
+            p_vaddr: 0x400000,
+            p_filesz: 0x1010,
+            p_memsz: 0x1010,
+            p_align: PAGE_SIZE,
+            ..Default::default()
+        };
+        let data = Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_W,
+            p_offset: 0x1010,
+            p_vaddr: 0x402010,
+            p_filesz: 8,
+            p_memsz: 0x100,
+            p_align: PAGE_SIZE,
+            ..Default::default()
+        };
+
+        let mut elf = vec![0; 0x1018];
+        elf[..ehdr_size as usize].copy_from_slice(pod_bytes(&ehdr));
+        elf[ehdr_size as usize..][..phdr_size as usize].copy_from_slice(pod_bytes(&text));
+        elf[(ehdr_size + phdr_size) as usize..][..phdr_size as usize].copy_from_slice(pod_bytes(&data));
+        elf[0x1000..0x1004].copy_from_slice(&[0x90, 0x90, 0xf4, 0xc3]);
+        elf[0x1010..0x1018].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn load_static_elf() {
+        let image = ElfImage::parse(sample_elf()).unwrap();
+        let mut mapper = BufferMapper::new(0x400000, 0x20000);
+        /* stale bytes where BSS goes must be cleared */
+        mapper.memory.as_mut_slice()[0x2020] = 0xff;
+        let stack = StackConfig {
+            top: 0x420000,
+            size: 0x4000,
+        };
+        let loaded = image.load(&mut mapper, stack, &["prog", "-v"], &["HOME=/"]).unwrap();
+
+        assert_eq!((loaded.entry, loaded.phdr, loaded.brk), (0x401000, 0x400040, 0x403000));
+        assert_eq!(mapper.read_u64(0x401000).unwrap() as u32, 0xc3f49090);
+        assert_eq!(mapper.read_u64(0x402010).unwrap(), 0x1122334455667788);
+        assert_eq!(mapper.memory().as_slice()[0x2020], 0);
+        assert_eq!(mapper.perm(0x401000), Some(PAGE_PERM_READ | PAGE_PERM_EXEC_USER));
+        assert_eq!(mapper.perm(0x402000), Some(PAGE_PERM_READ | PAGE_PERM_WRITE));
+
+        assert_eq!(loaded.rsp % 16, 0);
+        assert_eq!(mapper.read_u64(loaded.rsp).unwrap(), 2);
+        let argv0 = mapper.read_u64(loaded.rsp + 8).unwrap();
+        let mut name = [0; 5];
+        mapper.read(argv0, &mut name).unwrap();
+        assert_eq!(&name, b"prog\0");
+
+        let mut conf = DuneConfig::default();
+        loaded.apply(&mut conf);
+        assert_eq!((conf.rip(), conf.rsp()), (0x401000, loaded.rsp));
+    }
+
+    #[test]
+    fn rejects_bad_stacks() {
+        let image = ElfImage::parse(sample_elf()).unwrap();
+        let stacks = [
+            StackConfig { top: 0x1000, size: 0x2000 },
+            StackConfig { top: u64::MAX - 0x10, size: 0x1000 },
+        ];
+        for stack in stacks {
+            let mut mapper = BufferMapper::new(0x400000, 0x20000);
+            assert!(matches!(image.load(&mut mapper, stack, &[], &[]), Err(Error::InvalidInput(_))));
+        }
+
+        assert!(matches!(StackConfig::below_base_stack(&DuneLayout::default()), Err(Error::InvalidInput(_))));
+        let layout = DuneLayout::new(
+            x86_64::PhysAddr::new(1 << 40),
+            x86_64::VirtAddr::new(0x7f00_0000_0000),
+            x86_64::VirtAddr::new(0x7fff_0000_0000),
+        );
+        assert_eq!(StackConfig::below_base_stack(&layout).unwrap().top, 0x7ffe_ffff_f000);
+    }
+
+    #[test]
+    fn rejects_wrapping_addresses() {
+        let phdr_at = size_of::<Elf64Ehdr>() + size_of::<Elf64Phdr>();
+        let mut elf = sample_elf();
+        let vaddr = offset_of!(Elf64Phdr, p_vaddr);
+        elf[phdr_at + vaddr..][..8].copy_from_slice(&(u64::MAX - 0xff).to_le_bytes());
+        assert!(matches!(ElfImage::parse(elf), Err(Error::InvalidInput(_))));
+
+        /* fits exactly, but the end cannot be rounded up to a page */
+        let mut elf = sample_elf();
+        elf[phdr_at + vaddr..][..8].copy_from_slice(&(u64::MAX - 0x100).to_le_bytes());
+        assert!(matches!(ElfImage::parse(elf), Err(Error::InvalidInput(_))));
+
+        let mut elf = sample_elf();
+        elf[offset_of!(Elf64Ehdr, e_type)..][..2].copy_from_slice(&ET_DYN.to_le_bytes());
+        let image = ElfImage::parse(elf).unwrap();
+        assert!(matches!(image.with_bias(u64::MAX - 0x1000), Err(Error::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{Error, Result};
+
+/// Byte-level access to guest virtual memory.
+pub trait GuestMemory {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()>;
+
+    fn read_u64(&self, addr: u64) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write_u64(&mut self, addr: u64, value: u64) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+}
+
+/// Read a plain-old-data ABI struct out of guest memory.
+pub fn read_pod<T: Copy + Default, M: GuestMemory + ?Sized>(memory: &M, addr: u64) -> Result<T> {
+    let mut value = T::default();
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, std::mem::size_of::<T>())
+    };
+    memory.read(addr, bytes)?;
+    Ok(value)
+}
+
+/// Write a plain-old-data ABI struct into guest memory.
+pub fn write_pod<T: Copy, M: GuestMemory + ?Sized>(memory: &mut M, addr: u64, value: &T) -> Result<()> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    };
+    memory.write(addr, bytes)
+}
+
+/// Guest memory that is mapped at the same addresses in this process, as under Dune.
+///
+/// Accesses go through `process_vm_readv`/`process_vm_writev` on our own pid,
+/// so an unmapped address is reported as an error instead of faulting.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HostMemory;
+
+impl HostMemory {
+    pub fn new() -> Self {
+        HostMemory
+    }
+}
+
+impl GuestMemory for HostMemory {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as *mut _,
+            iov_len: buf.len(),
+        };
+        let ret = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if ret < 0 {
+            return Err(Error::last_memory(addr));
+        }
+        if ret as usize != buf.len() {
+            return Err(Error::InvalidAddress);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let local = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len: data.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as *mut _,
+            iov_len: data.len(),
+        };
+        let ret = unsafe { libc::process_vm_writev(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if ret < 0 {
+            return Err(Error::last_memory(addr));
+        }
+        if ret as usize != data.len() {
+            return Err(Error::InvalidAddress);
+        }
+        Ok(())
+    }
+}
+
+/// A contiguous range of guest memory backed by a local buffer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BufferMemory {
+    base: u64,
+    data: Vec<u8>,
+}
+
+impl BufferMemory {
+    pub fn new(base: u64, len: usize) -> Self {
+        Self {
+            base,
+            data: vec![0; len],
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.data.len() as u64
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn range(&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = addr.checked_sub(self.base).ok_or(Error::InvalidAddress)? as usize;
+        let end = start.checked_add(len).ok_or(Error::InvalidAddress)?;
+        if end > self.data.len() {
+            return Err(Error::InvalidAddress);
+        }
+        Ok(start..end)
+    }
+}
+
+impl GuestMemory for BufferMemory {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let range = self.range(addr, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+}
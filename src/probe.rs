@@ -0,0 +1,216 @@
+use std::arch::x86_64::{__cpuid, __cpuid_count};
+use std::fs;
+use std::path::Path;
+
+use crate::dev::{BaseDevice, Device, DUNE_DEVICE_PATH, VMPL_DEVICE_PATH};
+use crate::{Error, Result};
+
+const CPUID_FEATURES: u32 = 0x0000_0001;
+const CPUID_EXT_MAX: u32 = 0x8000_0000;
+const CPUID_EXT_FEATURES: u32 = 0x8000_0001;
+const CPUID_SEV_FEATURES: u32 = 0x8000_001f;
+
+const CPUID_1_ECX_VMX: u32 = 1 << 5;
+const CPUID_80000001_ECX_SVM: u32 = 1 << 2;
+const CPUID_8000001F_EAX_SEV: u32 = 1 << 1;
+const CPUID_8000001F_EAX_SEV_ES: u32 = 1 << 3;
+const CPUID_8000001F_EAX_SNP: u32 = 1 << 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BackendKind {
+    Dune,
+    Vmpl,
+}
+
+impl BackendKind {
+    pub fn device_path(&self) -> &'static str {
+        match self {
+            BackendKind::Dune => DUNE_DEVICE_PATH,
+            BackendKind::Vmpl => VMPL_DEVICE_PATH,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub vmx: bool,
+    pub svm: bool,
+    pub sev: bool,
+    pub sev_es: bool,
+    pub snp: bool,
+}
+
+impl CpuFeatures {
+    /// Read the virtualization feature bits straight from CPUID.
+    pub fn from_cpuid() -> Self {
+        let mut features = CpuFeatures::default();
+
+        let leaf1 = __cpuid(CPUID_FEATURES);
+        features.vmx = leaf1.ecx & CPUID_1_ECX_VMX != 0;
+
+        let max_ext = __cpuid(CPUID_EXT_MAX).eax;
+        if max_ext >= CPUID_EXT_FEATURES {
+            let ext = __cpuid(CPUID_EXT_FEATURES);
+            features.svm = ext.ecx & CPUID_80000001_ECX_SVM != 0;
+        }
+        if max_ext >= CPUID_SEV_FEATURES {
+            let sev = __cpuid_count(CPUID_SEV_FEATURES, 0);
+            features.sev = sev.eax & CPUID_8000001F_EAX_SEV != 0;
+            features.sev_es = sev.eax & CPUID_8000001F_EAX_SEV_ES != 0;
+            features.snp = sev.eax & CPUID_8000001F_EAX_SNP != 0;
+        }
+
+        features
+    }
+
+    /// Parse the `flags` line of `/proc/cpuinfo`.
+    pub fn from_cpuinfo(cpuinfo: &str) -> Self {
+        let mut features = CpuFeatures::default();
+        let flags = cpuinfo
+            .lines()
+            .find(|line| line.starts_with("flags"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, flags)| flags)
+            .unwrap_or("");
+
+        for flag in flags.split_whitespace() {
+            match flag {
+                "vmx" => features.vmx = true,
+                "svm" => features.svm = true,
+                "sev" => features.sev = true,
+                "sev_es" => features.sev_es = true,
+                "sev_snp" => features.snp = true,
+                _ => {}
+            }
+        }
+
+        features
+    }
+
+    pub fn union(&self, other: &CpuFeatures) -> Self {
+        CpuFeatures {
+            vmx: self.vmx || other.vmx,
+            svm: self.svm || other.svm,
+            sev: self.sev || other.sev,
+            sev_es: self.sev_es || other.sev_es,
+            snp: self.snp || other.snp,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub cpu: CpuFeatures,
+    pub dune_device: bool,
+    pub vmpl_device: bool,
+}
+
+impl Capabilities {
+    /// Probe CPUID, `/proc/cpuinfo` and the device nodes of both backends.
+    pub fn probe() -> Self {
+        let mut cpu = CpuFeatures::from_cpuid();
+        if let Ok(cpuinfo) = fs::read_to_string("/proc/cpuinfo") {
+            cpu = cpu.union(&CpuFeatures::from_cpuinfo(&cpuinfo));
+        }
+
+        Capabilities {
+            cpu,
+            dune_device: Path::new(DUNE_DEVICE_PATH).exists(),
+            vmpl_device: Path::new(VMPL_DEVICE_PATH).exists(),
+        }
+    }
+
+    pub fn dune_supported(&self) -> bool {
+        self.cpu.vmx && self.dune_device
+    }
+
+    pub fn vmpl_supported(&self) -> bool {
+        self.cpu.svm && self.cpu.sev && self.cpu.sev_es && self.cpu.snp && self.vmpl_device
+    }
+
+    /// Pick the backend to use, preferring Dune when both are available.
+    pub fn select(&self) -> Result<BackendKind> {
+        if self.dune_supported() {
+            return Ok(BackendKind::Dune);
+        }
+        if self.vmpl_supported() {
+            return Ok(BackendKind::Vmpl);
+        }
+        Err(Error::Unsupported(self.explain()))
+    }
+
+    fn explain(&self) -> String {
+        let mut missing = Vec::new();
+
+        if self.cpu.vmx {
+            missing.push(format!("dune: {} not found (is the dune module loaded?)", DUNE_DEVICE_PATH));
+        } else {
+            missing.push("dune: CPU lacks VT-x (vmx)".to_string());
+        }
+
+        if !self.cpu.svm {
+            missing.push("vmpl: CPU lacks AMD-V (svm)".to_string());
+        } else if !(self.cpu.sev && self.cpu.sev_es && self.cpu.snp) {
+            let absent: Vec<&str> = [
+                ("sev", self.cpu.sev),
+                ("sev_es", self.cpu.sev_es),
+                ("sev_snp", self.cpu.snp),
+            ]
+            .iter()
+            .filter(|(_, present)| !present)
+            .map(|(name, _)| *name)
+            .collect();
+            missing.push(format!("vmpl: CPU lacks {}", absent.join(", ")));
+        } else {
+            missing.push(format!("vmpl: {} not found (is the vmpl module loaded?)", VMPL_DEVICE_PATH));
+        }
+
+        format!("no usable backend: {}", missing.join("; "))
+    }
+}
+
+pub fn detect_backend() -> Result<BackendKind> {
+    Capabilities::probe().select()
+}
+
+/// Open the device node of whichever backend this host supports.
+pub fn open_default() -> Result<(BackendKind, BaseDevice)> {
+    let kind = detect_backend()?;
+    let mut device = BaseDevice::new();
+    device.open(kind.device_path())?;
+    Ok((kind, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTEL: &str = "processor\t: 0
+vendor_id\t: GenuineIntel
+model name\t: Intel(R) Xeon(R) CPU
+flags\t\t: fpu vme de pse tsc msr pae mce cx8 apic vmx smx est tm2 ssse3 sdbg fma
+bugs\t\t: spectre_v1 spectre_v2
+";
+
+    const AMD: &str = "processor\t: 0
+vendor_id\t: AuthenticAMD
+flags\t\t: fpu vme de pse tsc svm extapic cr8_legacy sev sev_es sev_snp
+";
+
+    #[test]
+    fn parses_cpuinfo_flags() {
+        let intel = CpuFeatures::from_cpuinfo(INTEL);
+        assert!(intel.vmx);
+        assert!(!intel.svm && !intel.sev && !intel.sev_es && !intel.snp);
+
+        let amd = CpuFeatures::from_cpuinfo(AMD);
+        assert!(!amd.vmx);
+        assert!(amd.svm && amd.sev && amd.sev_es && amd.snp);
+
+        let no_virt = AMD.replace(" svm", "").replace(" sev_snp", "");
+        let plain = CpuFeatures::from_cpuinfo(&no_virt);
+        assert!(!plain.svm && !plain.snp);
+        assert!(plain.sev_es);
+        assert_eq!(CpuFeatures::from_cpuinfo(""), CpuFeatures::default());
+    }
+}
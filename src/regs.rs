@@ -0,0 +1,366 @@
+use std::fmt;
+use std::ops::{Index, IndexMut};
+
+use libc::user_regs_struct;
+
+use crate::debug::DuneTrapRegs;
+use crate::dune::DuneConfig;
+use crate::funcs;
+use crate::trap::DuneTf;
+use crate::{Error, Result};
+
+pub const GENERAL_REGISTER_COUNT: usize = 18;
+
+macro_rules! general_registers {
+    ($($field:ident => $variant:ident),* $(,)?) => {
+        /// A general purpose register, in `DuneTrapRegs` order.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum Register {
+            $($variant),*
+        }
+
+        impl Register {
+            pub const ALL: [Register; GENERAL_REGISTER_COUNT] = [$(Register::$variant),*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Register::$variant => stringify!($field)),*
+                }
+            }
+        }
+
+        /// The register file shared by `DuneConfig`, `DuneTrapRegs` and `DuneTf`.
+        #[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+        pub struct GeneralRegisters {
+            $($field: u64),*
+        }
+
+        impl GeneralRegisters {
+            $(funcs!($field, u64);)*
+        }
+
+        impl Index<Register> for GeneralRegisters {
+            type Output = u64;
+
+            fn index(&self, reg: Register) -> &u64 {
+                match reg {
+                    $(Register::$variant => &self.$field),*
+                }
+            }
+        }
+
+        impl IndexMut<Register> for GeneralRegisters {
+            fn index_mut(&mut self, reg: Register) -> &mut u64 {
+                match reg {
+                    $(Register::$variant => &mut self.$field),*
+                }
+            }
+        }
+
+        impl From<&DuneTrapRegs> for GeneralRegisters {
+            fn from(regs: &DuneTrapRegs) -> Self {
+                Self {
+                    $($field: regs.$field()),*
+                }
+            }
+        }
+
+        impl From<&GeneralRegisters> for DuneTrapRegs {
+            fn from(regs: &GeneralRegisters) -> Self {
+                let mut out = DuneTrapRegs::default();
+                paste::paste! {
+                    $(out.[<set_ $field>](regs.$field);)*
+                }
+                out
+            }
+        }
+    };
+}
+
+general_registers! {
+    rax => Rax,
+    rbx => Rbx,
+    rcx => Rcx,
+    rdx => Rdx,
+    rsi => Rsi,
+    rdi => Rdi,
+    rsp => Rsp,
+    rbp => Rbp,
+    r8 => R8,
+    r9 => R9,
+    r10 => R10,
+    r11 => R11,
+    r12 => R12,
+    r13 => R13,
+    r14 => R14,
+    r15 => R15,
+    rip => Rip,
+    rflags => Rflags,
+}
+
+impl Register {
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl TryFrom<usize> for Register {
+    type Error = Error;
+
+    fn try_from(index: usize) -> Result<Self> {
+        Register::ALL
+            .get(index)
+            .copied()
+            .ok_or_else(|| Error::InvalidInput(format!("No register with index {}", index)))
+    }
+}
+
+impl TryFrom<&str> for Register {
+    type Error = Error;
+
+    fn try_from(name: &str) -> Result<Self> {
+        let name = name.to_ascii_lowercase();
+        let name = if name == "eflags" { "rflags" } else { name.as_str() };
+        Register::ALL
+            .iter()
+            .find(|reg| reg.name() == name)
+            .copied()
+            .ok_or_else(|| Error::InvalidInput(format!("Unknown register: {}", name)))
+    }
+}
+
+impl GeneralRegisters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, reg: Register) -> u64 {
+        self[reg]
+    }
+
+    pub fn set(&mut self, reg: Register, value: u64) -> &mut Self {
+        self[reg] = value;
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Register, u64)> + '_ {
+        Register::ALL.iter().map(move |&reg| (reg, self[reg]))
+    }
+
+    /// Overwrite the general registers of `regs`, keeping its other fields.
+    pub fn write_user_regs(&self, regs: &mut user_regs_struct) {
+        regs.rax = self.rax;
+        regs.rbx = self.rbx;
+        regs.rcx = self.rcx;
+        regs.rdx = self.rdx;
+        regs.rsi = self.rsi;
+        regs.rdi = self.rdi;
+        regs.rsp = self.rsp;
+        regs.rbp = self.rbp;
+        regs.r8 = self.r8;
+        regs.r9 = self.r9;
+        regs.r10 = self.r10;
+        regs.r11 = self.r11;
+        regs.r12 = self.r12;
+        regs.r13 = self.r13;
+        regs.r14 = self.r14;
+        regs.r15 = self.r15;
+        regs.rip = self.rip;
+        regs.eflags = self.rflags;
+    }
+}
+
+impl From<DuneTrapRegs> for GeneralRegisters {
+    fn from(regs: DuneTrapRegs) -> Self {
+        GeneralRegisters::from(&regs)
+    }
+}
+
+impl From<GeneralRegisters> for DuneTrapRegs {
+    fn from(regs: GeneralRegisters) -> Self {
+        DuneTrapRegs::from(&regs)
+    }
+}
+
+impl From<&DuneConfig> for GeneralRegisters {
+    fn from(conf: &DuneConfig) -> Self {
+        Self {
+            rax: conf.rax() as u64,
+            rbx: conf.rbx(),
+            rcx: conf.rcx(),
+            rdx: conf.rdx(),
+            rsi: conf.rsi(),
+            rdi: conf.rdi(),
+            rsp: conf.rsp(),
+            rbp: conf.rbp(),
+            r8: conf.r8(),
+            r9: conf.r9(),
+            r10: conf.r10(),
+            r11: conf.r11(),
+            r12: conf.r12(),
+            r13: conf.r13(),
+            r14: conf.r14(),
+            r15: conf.r15(),
+            rip: conf.rip(),
+            rflags: conf.rflags(),
+        }
+    }
+}
+
+impl From<&DuneTf> for GeneralRegisters {
+    fn from(tf: &DuneTf) -> Self {
+        Self {
+            rax: tf.rax(),
+            rbx: tf.rbx(),
+            rcx: tf.rcx(),
+            rdx: tf.rdx(),
+            rsi: tf.rsi(),
+            rdi: tf.rdi(),
+            rsp: tf.rsp(),
+            rbp: tf.rbp(),
+            r8: tf.r8(),
+            r9: tf.r9(),
+            r10: tf.r10(),
+            r11: tf.r11(),
+            r12: tf.r12(),
+            r13: tf.r13(),
+            r14: tf.r14(),
+            r15: tf.r15(),
+            rip: tf.rip(),
+            rflags: tf.rflags(),
+        }
+    }
+}
+
+impl From<&user_regs_struct> for GeneralRegisters {
+    fn from(regs: &user_regs_struct) -> Self {
+        Self {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.eflags,
+        }
+    }
+}
+
+impl DuneConfig {
+    pub fn general_registers(&self) -> GeneralRegisters {
+        GeneralRegisters::from(self)
+    }
+
+    /// Load the general registers, leaving ret, status, cr3 and vcpu untouched.
+    pub fn set_general_registers(&mut self, regs: &GeneralRegisters) -> &mut Self {
+        self.set_rax(regs.rax as i64)
+            .set_rbx(regs.rbx)
+            .set_rcx(regs.rcx)
+            .set_rdx(regs.rdx)
+            .set_rsi(regs.rsi)
+            .set_rdi(regs.rdi)
+            .set_rsp(regs.rsp)
+            .set_rbp(regs.rbp)
+            .set_r8(regs.r8)
+            .set_r9(regs.r9)
+            .set_r10(regs.r10)
+            .set_r11(regs.r11)
+            .set_r12(regs.r12)
+            .set_r13(regs.r13)
+            .set_r14(regs.r14)
+            .set_r15(regs.r15)
+            .set_rip(regs.rip)
+            .set_rflags(regs.rflags)
+    }
+}
+
+impl DuneTf {
+    pub fn general_registers(&self) -> GeneralRegisters {
+        GeneralRegisters::from(self)
+    }
+
+    /// Load the general registers, leaving err, cs and ss untouched.
+    pub fn set_general_registers(&mut self, regs: &GeneralRegisters) -> &mut Self {
+        self.set_rax(regs.rax)
+            .set_rbx(regs.rbx)
+            .set_rcx(regs.rcx)
+            .set_rdx(regs.rdx)
+            .set_rsi(regs.rsi)
+            .set_rdi(regs.rdi)
+            .set_rsp(regs.rsp)
+            .set_rbp(regs.rbp)
+            .set_r8(regs.r8)
+            .set_r9(regs.r9)
+            .set_r10(regs.r10)
+            .set_r11(regs.r11)
+            .set_r12(regs.r12)
+            .set_r13(regs.r13)
+            .set_r14(regs.r14)
+            .set_r15(regs.r15)
+            .set_rip(regs.rip)
+            .set_rflags(regs.rflags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> GeneralRegisters {
+        let mut regs = GeneralRegisters::new();
+        for (i, reg) in Register::ALL.iter().enumerate() {
+            regs[*reg] = 0x1000 + i as u64;
+        }
+        regs.set_rax(u64::MAX);
+        regs
+    }
+
+    #[test]
+    fn conversions_are_lossless() {
+        let regs = sample();
+
+        assert_eq!(GeneralRegisters::from(DuneTrapRegs::from(&regs)), regs);
+
+        let mut conf = DuneConfig::default();
+        conf.set_cr3(0xabc).set_general_registers(&regs);
+        assert_eq!(conf.general_registers(), regs);
+        assert_eq!(conf.cr3(), 0xabc);
+
+        let mut tf = DuneTf::default();
+        tf.set_cs(0x33).set_general_registers(&regs);
+        assert_eq!(tf.general_registers(), regs);
+        assert_eq!(tf.cs(), 0x33);
+
+        let mut user: user_regs_struct = unsafe { std::mem::zeroed() };
+        user.fs_base = 0x7777;
+        regs.write_user_regs(&mut user);
+        assert_eq!(GeneralRegisters::from(&user), regs);
+        assert_eq!(user.fs_base, 0x7777);
+    }
+
+    #[test]
+    fn register_lookup() {
+        assert_eq!(Register::try_from(16).unwrap(), Register::Rip);
+        assert!(Register::try_from(GENERAL_REGISTER_COUNT).is_err());
+        assert_eq!(Register::try_from("EFLAGS").unwrap(), Register::Rflags);
+        assert_eq!(Register::R12.index(), 12);
+        assert_eq!(sample().get(Register::R8), 0x1008);
+    }
+}
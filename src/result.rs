@@ -1,10 +1,18 @@
-use std::{fmt, io::ErrorKind};
+use std::fmt;
 use libc::c_int;
 use nix::errno::Errno;
 
 #[derive(Debug)]
 pub enum Error {
     LibcError(Errno),
+    /// An ioctl on a Dune or VMPL device failed.
+    Ioctl { name: &'static str, errno: Errno },
+    /// Opening or operating on a device node failed.
+    Device { path: String, errno: Errno },
+    /// A memory operation on a guest or host address failed.
+    Memory { addr: u64, errno: Errno },
+    /// Any other system call failed.
+    Syscall { name: &'static str, errno: Errno },
     Io(std::io::Error),
     InvalidInput(String),
     InvalidAddress,
@@ -13,6 +21,7 @@ pub enum Error {
     OutOfMemory,
     NotFound,
     PermissionDenied,
+    Unsupported(String),
     Unknown,
 }
 
@@ -20,6 +29,10 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::LibcError(err) => write!(f, "Libc error: {}", err),
+            Error::Ioctl { name, errno } => write!(f, "ioctl {} failed: {}", name, errno),
+            Error::Device { path, errno } => write!(f, "Device {}: {}", path, errno),
+            Error::Memory { addr, errno } => write!(f, "Memory at {:#x}: {}", addr, errno),
+            Error::Syscall { name, errno } => write!(f, "{} failed: {}", name, errno),
             Error::Io(err) => write!(f, "IO error: {}", err),
             Error::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
             Error::InvalidAddress => write!(f, "Invalid address"),
@@ -28,26 +41,84 @@ impl fmt::Display for Error {
             Error::OutOfMemory => write!(f, "Out of memory"),
             Error::NotFound => write!(f, "Not found"),
             Error::PermissionDenied => write!(f, "Permission denied"),
+            Error::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
             Error::Unknown => write!(f, "Unknown error"),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::LibcError(errno)
+            | Error::Ioctl { errno, .. }
+            | Error::Device { errno, .. }
+            | Error::Memory { errno, .. }
+            | Error::Syscall { errno, .. } => Some(errno),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// The errno behind this error, if it came from a failed system call.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::LibcError(errno)
+            | Error::Ioctl { errno, .. }
+            | Error::Device { errno, .. }
+            | Error::Memory { errno, .. }
+            | Error::Syscall { errno, .. } => Some(*errno as i32),
+            Error::Io(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    pub fn errno(&self) -> Option<Errno> {
+        self.raw_os_error().map(Errno::from_raw)
+    }
+
+    /// Attach an ioctl name to the errno of a failed nix ioctl call.
+    pub fn ioctl(name: &'static str) -> impl FnOnce(Errno) -> Self {
+        move |errno| Error::Ioctl { name, errno }
+    }
+
+    /// A failed ioctl, taking errno from the last system call.
+    pub fn last_ioctl(name: &'static str) -> Self {
+        Error::Ioctl {
+            name,
+            errno: Errno::last(),
+        }
+    }
+
+    pub fn last_device(path: &str) -> Self {
+        Error::Device {
+            path: path.to_string(),
+            errno: Errno::last(),
+        }
+    }
+
+    pub fn last_memory(addr: u64) -> Self {
+        Error::Memory {
+            addr,
+            errno: Errno::last(),
+        }
+    }
+
+    /// A failed system call, taking errno from the last system call.
+    pub fn last_syscall(name: &'static str) -> Self {
+        Error::Syscall {
+            name,
+            errno: Errno::last(),
+        }
+    }
+}
+
+/// An errno returned by a system call; the number is kept as is.
 impl From<c_int> for Error {
     fn from(err: c_int) -> Self {
-        match err {
-            libc::EPERM => Error::PermissionDenied,
-            libc::ENOENT => Error::NotFound,
-            libc::EINTR => Error::Unknown,
-            libc::EIO => Error::Io(std::io::Error::from(ErrorKind::Other)),
-            libc::ENOMEM => Error::InvalidInput("Out of memory".to_string()),
-            libc::EACCES => Error::PermissionDenied,
-            libc::EFAULT => Error::InvalidInput("Invalid pointer".to_string()),
-            libc::EEXIST => Error::InvalidInput("File already exists".to_string()),
-            libc::EINVAL => Error::InvalidInput("Invalid input".to_string()),
-            libc::ERANGE => Error::InvalidInput("Out of range".to_string()),
-            _ => Error::LibcError(Errno::from_raw(err)),
-        }
+        Error::LibcError(Errno::from_raw(err))
     }
 }
 
@@ -58,3 +129,37 @@ impl From<std::io::Error> for Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn errno_mapping_and_context() {
+        let errnos = [
+            libc::EPERM, libc::EACCES, libc::ENOENT, libc::ENOMEM, libc::EFAULT, libc::EEXIST,
+            libc::EINTR, libc::EIO, libc::EINVAL, libc::ERANGE,
+        ];
+        for errno in errnos {
+            assert_eq!(Error::from(errno).raw_os_error(), Some(errno));
+        }
+        assert_eq!(Error::from(libc::EACCES).to_string(), "Libc error: EACCES: Permission denied");
+
+        let err = Error::Ioctl {
+            name: "DUNE_ENTER",
+            errno: Errno::EFAULT,
+        };
+        assert_eq!(err.raw_os_error(), Some(libc::EFAULT));
+        assert_eq!(err.to_string(), "ioctl DUNE_ENTER failed: EFAULT: Bad address");
+        assert!(err.source().is_some());
+        assert_eq!(Error::InvalidAddress.raw_os_error(), None);
+
+        let err = Error::Syscall {
+            name: "timerfd_create",
+            errno: Errno::EMFILE,
+        };
+        assert_eq!(err.errno(), Some(Errno::EMFILE));
+        assert_eq!(err.to_string(), "timerfd_create failed: EMFILE: Too many open files");
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::ptr;
+
+use nix::errno::Errno;
+
+use crate::backend::PAGE_SIZE;
+use crate::idt::{IdtDescriptor, IDT_ENTRIES};
+use crate::timer::TickAction;
+use crate::trap::DuneTf;
+use crate::tss::Tss;
+use crate::{Error, Result};
+
+pub const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+
+/* IF set, plus the always-one bit 1 */
+const RFLAGS_DEFAULT: u64 = 0x202;
+
+/// An mmap'd stack with an inaccessible guard page below it.
+#[derive(Debug)]
+pub struct ThreadStack {
+    base: u64,
+    len: u64,
+}
+
+impl ThreadStack {
+    pub fn new(size: u64) -> Result<Self> {
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if size == 0 {
+            return Err(Error::InvalidInput("Stack size must not be zero".to_string()));
+        }
+        let len = size + PAGE_SIZE;
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(Error::last_syscall("mmap"));
+        }
+        if unsafe { libc::mprotect(base, PAGE_SIZE as usize, libc::PROT_NONE) } < 0 {
+            let errno = Errno::last();
+            unsafe { libc::munmap(base, len as usize) };
+            return Err(Error::Memory {
+                addr: base as u64,
+                errno,
+            });
+        }
+        Ok(Self {
+            base: base as u64,
+            len,
+        })
+    }
+
+    /// Lowest usable address, just above the guard page.
+    pub fn bottom(&self) -> u64 {
+        self.base + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> u64 {
+        self.base + self.len
+    }
+}
+
+impl Drop for ThreadStack {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut _, self.len as usize) };
+    }
+}
+
+pub type ThreadId = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadState {
+    Runnable,
+    Running,
+    Exited(u64),
+}
+
+/// Why the running thread gives up the CPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SwitchReason {
+    Yield,
+    Preempt,
+    Exit(u64),
+}
+
+#[derive(Debug)]
+struct GreenThread {
+    tf: DuneTf,
+    state: ThreadState,
+    /* None when the caller supplied the stack */
+    _stack: Option<ThreadStack>,
+}
+
+/// Green threads multiplexed on one vCPU inside guest mode.
+///
+/// Each thread's context is a `DuneTf`. A switch happens inside a guest
+/// interrupt or trap handler: the handler passes the frame it saved to
+/// `switch`, which stores it into the current thread and overwrites it with
+/// the next thread's, so returning from the handler resumes a different
+/// thread without a VM exit. Handlers should run on an IST stack (see
+/// `install`) so they never touch the stack of the thread being switched out.
+#[derive(Debug)]
+pub struct Scheduler {
+    threads: Vec<GreenThread>,
+    run_queue: VecDeque<ThreadId>,
+    current: Option<ThreadId>,
+    cs: u16,
+    ss: u16,
+    switches: u64,
+    interrupt_stack: Option<ThreadStack>,
+}
+
+impl Scheduler {
+    /// New threads start with the code and stack selectors of `template`.
+    pub fn new(template: &DuneTf) -> Self {
+        Self {
+            threads: Vec::new(),
+            run_queue: VecDeque::new(),
+            current: None,
+            cs: template.cs(),
+            ss: template.ss(),
+            switches: 0,
+            interrupt_stack: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<ThreadId> {
+        self.current
+    }
+
+    pub fn state(&self, id: ThreadId) -> Option<ThreadState> {
+        self.threads.get(id).map(|thread| thread.state)
+    }
+
+    pub fn context(&self, id: ThreadId) -> Option<&DuneTf> {
+        self.threads.get(id).map(|thread| &thread.tf)
+    }
+
+    pub fn switches(&self) -> u64 {
+        self.switches
+    }
+
+    /// Number of threads that have not exited.
+    pub fn live(&self) -> usize {
+        self.threads
+            .iter()
+            .filter(|thread| !matches!(thread.state, ThreadState::Exited(_)))
+            .count()
+    }
+
+    /// Start a thread at `entry(arg)` on a freshly allocated stack.
+    pub fn spawn(&mut self, entry: u64, arg: u64, stack_size: u64) -> Result<ThreadId> {
+        let stack = ThreadStack::new(stack_size)?;
+        let top = stack.top();
+        Ok(self.spawn_inner(entry, arg, top, Some(stack)))
+    }
+
+    /// Start a thread on a stack the caller manages.
+    pub fn spawn_on(&mut self, entry: u64, arg: u64, stack_top: u64) -> ThreadId {
+        self.spawn_inner(entry, arg, stack_top, None)
+    }
+
+    fn spawn_inner(&mut self, entry: u64, arg: u64, stack_top: u64, stack: Option<ThreadStack>) -> ThreadId {
+        let mut tf = DuneTf::default();
+        /* as if just called: rsp + 8 is 16-byte aligned */
+        tf.set_rip(entry)
+            .set_rdi(arg)
+            .set_rsp((stack_top & !0xf) - 8)
+            .set_rflags(RFLAGS_DEFAULT)
+            .set_cs(self.cs)
+            .set_ss(self.ss);
+
+        let id = self.threads.len();
+        self.threads.push(GreenThread {
+            tf,
+            state: ThreadState::Runnable,
+            _stack: stack,
+        });
+        self.run_queue.push_back(id);
+        id
+    }
+
+    /// Allocate an interrupt stack, put it in IST slot `ist` (1-7) and route `vectors` to it.
+    pub fn install(
+        &mut self,
+        idt: &mut [IdtDescriptor; IDT_ENTRIES],
+        tss: &mut Tss,
+        vectors: &[u8],
+        ist: u8,
+    ) -> Result<()> {
+        if !(1..=7).contains(&ist) {
+            return Err(Error::InvalidInput(format!("IST index {} out of range", ist)));
+        }
+        let stack = ThreadStack::new(DEFAULT_STACK_SIZE)?;
+        tss.set_tss_ist(ist as usize - 1, stack.top());
+        for &vector in vectors {
+            idt[vector as usize].set_ist(ist);
+        }
+        self.interrupt_stack = Some(stack);
+        Ok(())
+    }
+
+    /// Load the first runnable thread into `tf`, for the initial entry.
+    pub fn start(&mut self, tf: &mut DuneTf) -> Option<ThreadId> {
+        let next = self.run_queue.pop_front()?;
+        self.resume(next, tf);
+        Some(next)
+    }
+
+    fn resume(&mut self, id: ThreadId, tf: &mut DuneTf) {
+        let thread = &mut self.threads[id];
+        thread.state = ThreadState::Running;
+        *tf = thread.tf;
+        self.current = Some(id);
+    }
+
+    /// Save `tf` into the current thread and load the next runnable one.
+    ///
+    /// Returns the thread now in `tf`, or `None` once every thread has exited.
+    /// When nothing else is runnable the current thread keeps running.
+    pub fn switch(&mut self, tf: &mut DuneTf, reason: SwitchReason) -> Option<ThreadId> {
+        let prev = self.current.take();
+        if let Some(id) = prev {
+            let thread = &mut self.threads[id];
+            thread.tf = *tf;
+            match reason {
+                SwitchReason::Exit(code) => thread.state = ThreadState::Exited(code),
+                SwitchReason::Yield | SwitchReason::Preempt => {
+                    thread.state = ThreadState::Runnable;
+                    self.run_queue.push_back(id);
+                }
+            }
+        }
+
+        let next = self.run_queue.pop_front()?;
+        if Some(next) != prev {
+            self.switches += 1;
+        }
+        self.resume(next, tf);
+        Some(next)
+    }
+
+    /// Cooperative yield, called from the guest's yield trap.
+    pub fn yield_now(&mut self, tf: &mut DuneTf) -> Option<ThreadId> {
+        self.switch(tf, SwitchReason::Yield)
+    }
+
+    /// The current thread returned or called exit.
+    pub fn exit_current(&mut self, tf: &mut DuneTf, code: u64) -> Option<ThreadId> {
+        self.switch(tf, SwitchReason::Exit(code))
+    }
+
+    /// Act on a timer tick taken while `tf` was running.
+    pub fn on_tick(&mut self, tf: &mut DuneTf, action: TickAction) -> Option<ThreadId> {
+        match action {
+            TickAction::Continue => self.current,
+            TickAction::Preempt => self.switch(tf, SwitchReason::Preempt),
+            TickAction::Stop => {
+                /* park the current thread; the run loop decides what happens next */
+                if let Some(id) = self.current.take() {
+                    self.threads[id].tf = *tf;
+                    self.threads[id].state = ThreadState::Runnable;
+                    self.run_queue.push_front(id);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_robin_switch() {
+        let mut template = DuneTf::default();
+        template.set_cs(0x8).set_ss(0x10);
+        let mut sched = Scheduler::new(&template);
+        let a = sched.spawn_on(0x1000, 1, 0x20000);
+        let b = sched.spawn(0x2000, 2, DEFAULT_STACK_SIZE).unwrap();
+
+        let mut tf = DuneTf::default();
+        assert_eq!(sched.start(&mut tf), Some(a));
+        assert_eq!((tf.rip(), tf.rdi(), tf.rsp(), tf.cs()), (0x1000, 1, 0x1fff8, 0x8));
+
+        tf.set_rip(0x1010).set_rax(7);
+        assert_eq!(sched.on_tick(&mut tf, TickAction::Preempt), Some(b));
+        assert_eq!((tf.rip(), tf.rdi()), (0x2000, 2));
+        assert_eq!((tf.rsp() + 8) % 16, 0);
+
+        assert_eq!(sched.yield_now(&mut tf), Some(a));
+        assert_eq!((tf.rip(), tf.rax()), (0x1010, 7));
+
+        assert_eq!(sched.exit_current(&mut tf, 0), Some(b));
+        assert_eq!(sched.state(a), Some(ThreadState::Exited(0)));
+        assert_eq!(sched.yield_now(&mut tf), Some(b));
+        assert_eq!(sched.exit_current(&mut tf, 3), None);
+        assert_eq!((sched.live(), sched.switches()), (0, 3));
+    }
+
+    #[test]
+    fn install_fills_ist_slot() {
+        use crate::tss::TSS_IST;
+
+        for ist in [1u8, 7] {
+            let mut sched = Scheduler::new(&DuneTf::default());
+            let mut idt = [IdtDescriptor::default(); IDT_ENTRIES];
+            let mut tss = Tss::default();
+            sched.install(&mut idt, &mut tss, &[14], ist).unwrap();
+
+            let top = sched.interrupt_stack.as_ref().unwrap().top();
+            let slot = TSS_IST + (ist as usize - 1) * 8;
+            let bytes = unsafe {
+                std::slice::from_raw_parts(&tss as *const Tss as *const u8, std::mem::size_of::<Tss>())
+            };
+            assert_eq!(u64::from_le_bytes(bytes[slot..slot + 8].try_into().unwrap()), top);
+            assert_eq!(idt[14].ist(), ist);
+        }
+
+        let mut sched = Scheduler::new(&DuneTf::default());
+        let mut idt = [IdtDescriptor::default(); IDT_ENTRIES];
+        assert!(sched.install(&mut idt, &mut Tss::default(), &[14], 8).is_err());
+    }
+}
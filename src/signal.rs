@@ -0,0 +1,390 @@
+use std::mem::{offset_of, size_of};
+
+use nix::errno::Errno;
+
+use crate::dev::DUNE_SIGNAL_INTR_BASE;
+use crate::dune::{DuneConfig, DuneRetCode};
+use crate::funcs;
+use crate::idt::{IdtDescriptor, IDT_ENTRIES};
+use crate::mem::{read_pod, write_pod, GuestMemory};
+use crate::trap::DuneTf;
+use crate::{Error, Result};
+
+/// Highest signal number that still fits below vector 256.
+pub const SIGNAL_MAX: i32 = (IDT_ENTRIES as u64 - 1 - DUNE_SIGNAL_INTR_BASE) as i32;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Do not block the signal while its own handler runs.
+pub const SA_NODEFER: u32 = libc::SA_NODEFER as u32;
+/// Reset the action to `SIG_DFL` once the handler has been entered.
+pub const SA_RESETHAND: u32 = libc::SA_RESETHAND as u32;
+
+/* the x86-64 ABI leaves 128 bytes below rsp to leaf functions */
+const RED_ZONE: u64 = 128;
+
+/// RFLAGS bits a handler may change through its frame, as Linux's
+/// `FIX_EFLAGS`: CF, PF, AF, ZF, SF, TF, DF, OF, RF and AC.
+const SIGRETURN_RFLAGS: u64 = (1 << 0) | (1 << 2) | (1 << 4) | (1 << 6) | (1 << 7) | (1 << 8)
+    | (1 << 10) | (1 << 11) | (1 << 16) | (1 << 18);
+
+/// The guest IDT vector a host signal is reflected on.
+pub fn signal_vector(signo: i32) -> Option<u8> {
+    if (1..=SIGNAL_MAX).contains(&signo) {
+        Some((DUNE_SIGNAL_INTR_BASE + signo as u64) as u8)
+    } else {
+        None
+    }
+}
+
+/// The signal number behind a guest IDT vector, if it is a signal vector.
+pub fn vector_signal(vector: u8) -> Option<i32> {
+    let signo = (vector as u64).checked_sub(DUNE_SIGNAL_INTR_BASE)? as i32;
+    (1..=SIGNAL_MAX).contains(&signo).then_some(signo)
+}
+
+fn sigbit(signo: i32) -> u64 {
+    1 << (signo - 1)
+}
+
+fn check_signo(signo: i32) -> Result<()> {
+    if (1..=SIGNAL_MAX).contains(&signo) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput(format!("Signal {} has no guest vector", signo)))
+    }
+}
+
+/// What the kernel would do with a signal that has no handler installed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    CoreDump,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl DefaultAction {
+    pub fn of(signo: i32) -> Self {
+        match signo {
+            libc::SIGQUIT | libc::SIGILL | libc::SIGTRAP | libc::SIGABRT | libc::SIGBUS
+            | libc::SIGFPE | libc::SIGSEGV | libc::SIGXCPU | libc::SIGXFSZ | libc::SIGSYS => {
+                DefaultAction::CoreDump
+            }
+            libc::SIGCHLD | libc::SIGURG | libc::SIGWINCH => DefaultAction::Ignore,
+            libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU => DefaultAction::Stop,
+            libc::SIGCONT => DefaultAction::Continue,
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
+/// A guest handler registration, the guest-side view of `struct sigaction`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct GuestSigAction {
+    pub handler: u64,
+    pub restorer: u64,
+    pub mask: u64,
+    pub flags: u32,
+}
+
+impl GuestSigAction {
+    pub fn handler(handler: u64, restorer: u64) -> Self {
+        Self {
+            handler,
+            restorer,
+            ..Default::default()
+        }
+    }
+
+    pub fn ignore() -> Self {
+        Self {
+            handler: SIG_IGN,
+            ..Default::default()
+        }
+    }
+}
+
+/// Pushed on the guest stack before a handler runs.
+///
+/// The handler is entered with rsp pointing at `restorer`, so returning from
+/// it lands in the restorer, which issues `rt_sigreturn`.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SignalFrame {
+    restorer: u64,
+    signo: u64,
+    saved_mask: u64,
+    tf: DuneTf,
+}
+
+impl SignalFrame {
+    funcs!(restorer, u64);
+    funcs!(signo, u64);
+    funcs!(saved_mask, u64);
+
+    pub fn tf(&self) -> DuneTf {
+        self.tf
+    }
+}
+
+/// Result of trying to deliver a signal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// A frame was pushed at this address and the trap frame now enters the handler.
+    Handled { frame: u64 },
+    /// The signal is blocked and stays pending.
+    Deferred,
+    Ignored,
+    /// No handler; the caller carries out the default action.
+    Default(DefaultAction),
+}
+
+/// Reflects host signals into the guest and runs guest-registered handlers.
+///
+/// Signals arrive either as a `DUNE_RET_SIGNAL` exit or as an interrupt on
+/// vector `DUNE_SIGNAL_INTR_BASE + signo` handled by a guest IDT stub; both
+/// end up in `deliver`, which rewrites the saved trap frame to enter the
+/// handler. The guest's `rt_sigreturn` comes back through `sigreturn`.
+#[derive(Debug, Clone)]
+pub struct SignalManager {
+    actions: Vec<GuestSigAction>,
+    blocked: u64,
+    pending: u64,
+}
+
+impl Default for SignalManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalManager {
+    pub fn new() -> Self {
+        Self {
+            actions: vec![GuestSigAction::default(); SIGNAL_MAX as usize + 1],
+            blocked: 0,
+            pending: 0,
+        }
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn action(&self, signo: i32) -> Option<&GuestSigAction> {
+        check_signo(signo).ok()?;
+        self.actions.get(signo as usize)
+    }
+
+    /// Register a guest action and return the previous one.
+    pub fn set_action(&mut self, signo: i32, action: GuestSigAction) -> Result<GuestSigAction> {
+        check_signo(signo)?;
+        if signo == libc::SIGKILL || signo == libc::SIGSTOP {
+            return Err(Error::InvalidInput(format!("Signal {} cannot be caught", signo)));
+        }
+        Ok(std::mem::replace(&mut self.actions[signo as usize], action))
+    }
+
+    /// Replace the blocked mask, like `sigprocmask(SIG_SETMASK)`, and return the old one.
+    pub fn set_blocked(&mut self, mask: u64) -> u64 {
+        let unblockable = sigbit(libc::SIGKILL) | sigbit(libc::SIGSTOP);
+        std::mem::replace(&mut self.blocked, mask & !unblockable)
+    }
+
+    /// Point the gate of every signal vector at a guest entry stub.
+    ///
+    /// `stub` returns the guest address of the stub for a signal, which saves
+    /// a `DuneTf` and hands it to the runtime for `deliver`.
+    pub fn install_handlers<F>(&self, idt: &mut [IdtDescriptor; IDT_ENTRIES], selector: u16, stub: F)
+    where
+        F: Fn(i32) -> usize,
+    {
+        for signo in 1..=SIGNAL_MAX {
+            let vector = signal_vector(signo).unwrap() as usize;
+            idt[vector]
+                .clear()
+                .set_idt_addr(stub(signo))
+                .set_selector(selector)
+                .set_type_attr(0x8e); /* present, DPL 0, interrupt gate */
+        }
+    }
+
+    /// The signal number carried by an exit, if it was a signal exit.
+    pub fn exit_signal(conf: &DuneConfig) -> Option<i32> {
+        match DuneRetCode::from(conf.ret()) {
+            DuneRetCode::Signal => Some(conf.status() as i32),
+            _ => None,
+        }
+    }
+
+    /// Whether an exit is the guest calling `rt_sigreturn`.
+    pub fn is_sigreturn(conf: &DuneConfig) -> bool {
+        DuneRetCode::from(conf.ret()) == DuneRetCode::Syscall && conf.rax() == libc::SYS_rt_sigreturn
+    }
+
+    /// Deliver `signo` to the context in `tf`.
+    pub fn deliver(&mut self, tf: &mut DuneTf, signo: i32, memory: &mut dyn GuestMemory) -> Result<Delivery> {
+        check_signo(signo)?;
+        if self.blocked & sigbit(signo) != 0 {
+            self.pending |= sigbit(signo);
+            return Ok(Delivery::Deferred);
+        }
+        self.pending &= !sigbit(signo);
+
+        let action = self.actions[signo as usize];
+        match action.handler {
+            SIG_IGN => return Ok(Delivery::Ignored),
+            SIG_DFL => {
+                return Ok(match DefaultAction::of(signo) {
+                    DefaultAction::Ignore => Delivery::Ignored,
+                    default => Delivery::Default(default),
+                })
+            }
+            _ => {}
+        }
+
+        /* the handler's rsp + 8 must be 16-byte aligned, as after a call */
+        let frame_addr = tf
+            .rsp()
+            .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+            .and_then(|top| (top & !0xf).checked_sub(8))
+            .ok_or(Error::Memory { addr: tf.rsp(), errno: Errno::EFAULT })?;
+        let frame = SignalFrame {
+            restorer: action.restorer,
+            signo: signo as u64,
+            saved_mask: self.blocked,
+            tf: *tf,
+        };
+        write_pod(memory, frame_addr, &frame)?;
+
+        tf.set_rip(action.handler)
+            .set_rsp(frame_addr)
+            .set_rdi(signo as u64)
+            .set_rsi(frame_addr + offset_of!(SignalFrame, tf) as u64)
+            .set_rdx(0);
+
+        self.blocked |= action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            self.blocked |= sigbit(signo);
+        }
+        if action.flags & SA_RESETHAND != 0 {
+            self.actions[signo as usize] = GuestSigAction::default();
+        }
+        Ok(Delivery::Handled { frame: frame_addr })
+    }
+
+    /// Unwind the frame of a returning handler and restore the interrupted context.
+    ///
+    /// The handler's `ret` already popped the restorer address, so the frame
+    /// starts 8 bytes below the current rsp.
+    ///
+    /// The frame is guest-writable, so like Linux this keeps the current
+    /// segment selectors and only takes the user-modifiable RFLAGS bits.
+    pub fn sigreturn(&mut self, tf: &mut DuneTf, memory: &dyn GuestMemory) -> Result<()> {
+        let frame_addr = tf
+            .rsp()
+            .checked_sub(8)
+            .ok_or(Error::Memory { addr: tf.rsp(), errno: Errno::EFAULT })?;
+        let frame: SignalFrame = read_pod(memory, frame_addr)?;
+        let (cs, ss, rflags) = (tf.cs(), tf.ss(), tf.rflags());
+        *tf = frame.tf;
+        tf.set_cs(cs)
+            .set_ss(ss)
+            .set_rflags((rflags & !SIGRETURN_RFLAGS) | (frame.tf.rflags() & SIGRETURN_RFLAGS));
+        self.set_blocked(frame.saved_mask);
+        Ok(())
+    }
+
+    /// The lowest pending signal that is no longer blocked.
+    pub fn next_pending(&self) -> Option<i32> {
+        let ready = self.pending & !self.blocked;
+        (ready != 0).then(|| ready.trailing_zeros() as i32 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+    use crate::trap::{DUNE_TF_CS, DUNE_TF_RFLAGS, DUNE_TF_SS};
+
+    #[test]
+    fn vectors_roundtrip() {
+        assert_eq!(signal_vector(libc::SIGALRM), Some(214));
+        assert_eq!(vector_signal(214), Some(libc::SIGALRM));
+        assert_eq!(vector_signal(14), None);
+        assert_eq!(signal_vector(SIGNAL_MAX + 1), None);
+    }
+
+    #[test]
+    fn deliver_and_sigreturn() {
+        let mut memory = BufferMemory::new(0x10000, 0x1000);
+        let mut signals = SignalManager::new();
+        signals
+            .set_action(libc::SIGALRM, GuestSigAction::handler(0x401000, 0x402000))
+            .unwrap();
+
+        let mut tf = DuneTf::default();
+        tf.set_rip(0x400123).set_rsp(0x10f00).set_rax(42);
+        let Delivery::Handled { frame } = signals.deliver(&mut tf, libc::SIGALRM, &mut memory).unwrap() else {
+            panic!("signal not handled");
+        };
+        assert_eq!((tf.rip(), tf.rsp(), tf.rdi()), (0x401000, frame, libc::SIGALRM as u64));
+        assert_eq!((frame + 8) % 16, 0);
+        assert!(frame + (size_of::<SignalFrame>() as u64) <= 0x10f00 - RED_ZONE);
+        assert_eq!(memory.read_u64(frame).unwrap(), 0x402000);
+
+        /* blocked while the handler runs */
+        assert_eq!(
+            signals.deliver(&mut tf, libc::SIGALRM, &mut memory).unwrap(),
+            Delivery::Deferred
+        );
+
+        /* the handler returns into the restorer, which calls rt_sigreturn */
+        tf.set_rsp(frame + 8);
+        signals.sigreturn(&mut tf, &memory).unwrap();
+        assert_eq!((tf.rip(), tf.rsp(), tf.rax()), (0x400123, 0x10f00, 42));
+        assert_eq!(signals.blocked(), 0);
+        assert_eq!(signals.next_pending(), Some(libc::SIGALRM));
+    }
+
+    #[test]
+    fn sigreturn_sanitizes_frame() {
+        let mut memory = BufferMemory::new(0x10000, 0x1000);
+        let mut signals = SignalManager::new();
+        signals
+            .set_action(libc::SIGUSR1, GuestSigAction::handler(0x401000, 0x402000))
+            .unwrap();
+
+        let mut tf = DuneTf::default();
+        tf.set_rsp(0x10f00).set_cs(0x33).set_ss(0x2b).set_rflags(0x202);
+        let Delivery::Handled { frame } = signals.deliver(&mut tf, libc::SIGUSR1, &mut memory).unwrap() else {
+            panic!("signal not handled");
+        };
+
+        /* the handler forges a kernel selector and IOPL 3 alongside CF */
+        let saved = frame + offset_of!(SignalFrame, tf) as u64;
+        memory.write(saved + DUNE_TF_CS as u64, &0x10u16.to_le_bytes()).unwrap();
+        memory.write(saved + DUNE_TF_SS as u64, &0x18u16.to_le_bytes()).unwrap();
+        memory.write_u64(saved + DUNE_TF_RFLAGS as u64, 0x3203).unwrap();
+
+        tf.set_rsp(frame + 8);
+        signals.sigreturn(&mut tf, &memory).unwrap();
+        assert_eq!((tf.cs(), tf.ss(), tf.rflags()), (0x33, 0x2b, 0x203));
+
+        tf.set_rsp(0x40);
+        assert!(matches!(
+            signals.deliver(&mut tf, libc::SIGUSR1, &mut memory),
+            Err(Error::Memory { addr: 0x40, errno: Errno::EFAULT })
+        ));
+        tf.set_rsp(0);
+        assert!(signals.sigreturn(&mut tf, &memory).is_err());
+    }
+}
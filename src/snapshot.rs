@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+use nix::errno::Errno;
+
+use crate::backend::PAGE_SIZE;
+use crate::dune::DuneConfig;
+use crate::idt::{IdtDescriptor, IDT_ENTRIES};
+use crate::mem::GuestMemory;
+use crate::tss::Tss;
+use crate::vmpl::{GetPages, VcpuConfig};
+use crate::{Error, Result};
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"DUNESNAP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const TAG_REGS: [u8; 4] = *b"REGS";
+const TAG_VCPU: [u8; 4] = *b"VCPU";
+const TAG_IDT: [u8; 4] = *b"IDT ";
+const TAG_TSS: [u8; 4] = *b"TSS ";
+const TAG_PAGES: [u8; 4] = *b"PAGE";
+
+/* ABI structs are plain old data, so their bytes are their serialized form */
+fn pod_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn pod_from_bytes<T: Default>(bytes: &[u8], what: &str) -> Result<T> {
+    if bytes.len() != size_of::<T>() {
+        return Err(Error::InvalidInput(format!(
+            "Snapshot {} section is {} bytes, expected {}",
+            what,
+            bytes.len(),
+            size_of::<T>()
+        )));
+    }
+    let mut value = T::default();
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut value as *mut T as *mut u8, bytes.len());
+    }
+    Ok(value)
+}
+
+fn write_section<W: Write>(w: &mut W, tag: [u8; 4], payload: &[u8]) -> Result<()> {
+    w.write_all(&tag)?;
+    w.write_all(&0u32.to_le_bytes())?; /* flags, reserved */
+    w.write_all(&(payload.len() as u64).to_le_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// A checkpoint of a guest: registers, vCPU/IDT/TSS state and memory pages.
+///
+/// The on-disk format is a header followed by tagged, length-prefixed
+/// sections, so readers skip sections they do not know about.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    config: DuneConfig,
+    vcpu: Option<VcpuConfig>,
+    idt: Vec<IdtDescriptor>,
+    tss: Option<Tss>,
+    pages: BTreeMap<u64, Vec<u8>>,
+}
+
+/// Coalesce ascending page addresses into `(start, len)` runs.
+pub(crate) fn page_runs(pages: impl IntoIterator<Item = u64>) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for page in pages {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == page => *len += PAGE_SIZE,
+            _ => runs.push((page, PAGE_SIZE)),
+        }
+    }
+    runs
+}
+
+impl Snapshot {
+    pub fn new(config: &DuneConfig) -> Self {
+        Self {
+            config: config.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &DuneConfig {
+        &self.config
+    }
+
+    pub fn vcpu(&self) -> Option<&VcpuConfig> {
+        self.vcpu.as_ref()
+    }
+
+    pub fn idt(&self) -> &[IdtDescriptor] {
+        &self.idt
+    }
+
+    pub fn tss(&self) -> Option<&Tss> {
+        self.tss.as_ref()
+    }
+
+    pub fn set_vcpu(&mut self, vcpu: &VcpuConfig) -> &mut Self {
+        self.vcpu = Some(*vcpu);
+        self
+    }
+
+    pub fn set_idt(&mut self, idt: &[IdtDescriptor; IDT_ENTRIES]) -> &mut Self {
+        self.idt = idt.to_vec();
+        self
+    }
+
+    pub fn set_tss(&mut self, tss: &Tss) -> &mut Self {
+        self.tss = Some(*tss);
+        self
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.pages.iter().map(|(addr, data)| (*addr, data.as_slice()))
+    }
+
+    pub fn page(&self, addr: u64) -> Option<&[u8]> {
+        self.pages.get(&(addr & !(PAGE_SIZE - 1))).map(|data| data.as_slice())
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Copy the pages covering `[start, start + len)` out of guest memory.
+    pub fn capture_range(&mut self, memory: &dyn GuestMemory, start: u64, len: u64) -> Result<&mut Self> {
+        let first = start & !(PAGE_SIZE - 1);
+        let end = start
+            .checked_add(len)
+            .filter(|end| end.checked_add(PAGE_SIZE - 1).is_some())
+            .ok_or_else(|| Error::InvalidInput(format!("Range {:#x}+{:#x} overflows", start, len)))?;
+        let mut addr = first;
+        while addr < end {
+            let mut data = vec![0; PAGE_SIZE as usize];
+            memory.read(addr, &mut data)?;
+            self.pages.insert(addr, data);
+            addr += PAGE_SIZE;
+        }
+        Ok(self)
+    }
+
+    /// Capture a block of pages handed out by `Backend::alloc_pages`.
+    pub fn capture_pages(&mut self, memory: &dyn GuestMemory, pages: &GetPages) -> Result<&mut Self> {
+        self.capture_range(memory, pages.mapping(), pages.num_pages() * PAGE_SIZE)
+    }
+
+    /// Contiguous runs of captured pages as `(start, len)`.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        page_runs(self.pages.keys().copied())
+    }
+
+    /// Map the captured ranges in this process, for restoring into a fresh one.
+    ///
+    /// Fails with `EEXIST` if a range overlaps an existing mapping, e.g. when
+    /// restoring into the process that took the snapshot, where the pages
+    /// are already there. Ranges mapped before the failure are unmapped again.
+    pub fn map_host_pages(&self) -> Result<()> {
+        let mut mapped = Vec::new();
+        for (start, len) in self.ranges() {
+            let addr = unsafe {
+                libc::mmap(
+                    start as *mut _,
+                    len as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                    -1,
+                    0,
+                )
+            };
+            let errno = if addr == libc::MAP_FAILED {
+                Some(Errno::last())
+            } else if addr as u64 != start {
+                /* kernels without MAP_FIXED_NOREPLACE treat the address as a hint */
+                unsafe { libc::munmap(addr, len as usize) };
+                Some(Errno::EEXIST)
+            } else {
+                None
+            };
+            if let Some(errno) = errno {
+                for (start, len) in mapped {
+                    unsafe { libc::munmap(start as *mut _, len as usize) };
+                }
+                return Err(Error::Memory { addr: start, errno });
+            }
+            mapped.push((start, len));
+        }
+        Ok(())
+    }
+
+    /// Write every page back into guest memory and return the saved registers.
+    pub fn restore(&self, memory: &mut dyn GuestMemory) -> Result<DuneConfig> {
+        for (addr, data) in &self.pages {
+            memory.write(*addr, data)?;
+        }
+        Ok(self.config.clone())
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut sections = 1 + self.ranges().len() as u32;
+        sections += self.vcpu.is_some() as u32 + !self.idt.is_empty() as u32 + self.tss.is_some() as u32;
+
+        w.write_all(&SNAPSHOT_MAGIC)?;
+        w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        w.write_all(&sections.to_le_bytes())?;
+
+        write_section(w, TAG_REGS, pod_bytes(&self.config))?;
+        if let Some(vcpu) = &self.vcpu {
+            write_section(w, TAG_VCPU, pod_bytes(vcpu))?;
+        }
+        if !self.idt.is_empty() {
+            let idt: Vec<u8> = self.idt.iter().flat_map(|desc| desc.as_ref().to_vec()).collect();
+            write_section(w, TAG_IDT, &idt)?;
+        }
+        if let Some(tss) = &self.tss {
+            write_section(w, TAG_TSS, pod_bytes(tss))?;
+        }
+        for (start, len) in self.ranges() {
+            let mut payload = Vec::with_capacity(8 + len as usize);
+            payload.extend_from_slice(&start.to_le_bytes());
+            for addr in (start..start + len).step_by(PAGE_SIZE as usize) {
+                payload.extend_from_slice(&self.pages[&addr]);
+            }
+            write_section(w, TAG_PAGES, &payload)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let mut header = [0; 16];
+        r.read_exact(&mut header)?;
+        if header[..8] != SNAPSHOT_MAGIC {
+            return Err(Error::InvalidInput("Not a snapshot".to_string()));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(Error::InvalidInput(format!("Unsupported snapshot version {}", version)));
+        }
+        let sections = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let mut snapshot = Snapshot::default();
+        let mut have_regs = false;
+        for _ in 0..sections {
+            let mut section = [0; 16];
+            r.read_exact(&mut section)?;
+            let tag: [u8; 4] = section[..4].try_into().unwrap();
+            let len = u64::from_le_bytes(section[8..16].try_into().unwrap());
+            let mut payload = Vec::new();
+            r.by_ref().take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                return Err(Error::InvalidInput("Truncated snapshot".to_string()));
+            }
+
+            match tag {
+                TAG_REGS => {
+                    snapshot.config = pod_from_bytes(&payload, "register")?;
+                    have_regs = true;
+                }
+                TAG_VCPU => snapshot.vcpu = Some(pod_from_bytes(&payload, "vcpu")?),
+                TAG_TSS => snapshot.tss = Some(pod_from_bytes(&payload, "tss")?),
+                TAG_IDT => {
+                    snapshot.idt = payload
+                        .chunks(size_of::<IdtDescriptor>())
+                        .map(|chunk| pod_from_bytes(chunk, "idt"))
+                        .collect::<Result<_>>()?;
+                }
+                TAG_PAGES => {
+                    if payload.len() < 8 || (payload.len() - 8) % PAGE_SIZE as usize != 0 {
+                        return Err(Error::InvalidInput("Malformed snapshot page section".to_string()));
+                    }
+                    let start = u64::from_le_bytes(payload[..8].try_into().unwrap());
+                    let len = (payload.len() - 8) as u64;
+                    if start % PAGE_SIZE != 0 || start.checked_add(len).is_none() {
+                        return Err(Error::InvalidInput(format!(
+                            "Snapshot page range {:#x}+{:#x} is invalid",
+                            start, len
+                        )));
+                    }
+                    for (i, page) in payload[8..].chunks(PAGE_SIZE as usize).enumerate() {
+                        let addr = start + i as u64 * PAGE_SIZE;
+                        if snapshot.pages.insert(addr, page.to_vec()).is_some() {
+                            return Err(Error::InvalidInput(format!(
+                                "Snapshot page {:#x} appears twice",
+                                addr
+                            )));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !have_regs {
+            return Err(Error::InvalidInput("Snapshot has no register section".to_string()));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write_to(&mut BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut memory = BufferMemory::new(0x10000, 3 * PAGE_SIZE as usize);
+        memory.write(0x10010, b"before").unwrap();
+
+        let mut conf = DuneConfig::default();
+        conf.set_rip(0x401000).set_rsp(0x7000).set_cr3(0x1000);
+        let mut idt = [IdtDescriptor::default(); IDT_ENTRIES];
+        idt[14].set_idt_addr(0xdeadbeef);
+        let mut tss = Tss::default();
+        tss.set_tss_ist(1, 0x9000);
+
+        let mut snapshot = Snapshot::new(&conf);
+        snapshot
+            .set_idt(&idt)
+            .set_tss(&tss)
+            .capture_range(&memory, 0x10000, PAGE_SIZE)
+            .unwrap()
+            .capture_range(&memory, 0x12000, 1)
+            .unwrap();
+        assert_eq!(snapshot.ranges(), vec![(0x10000, PAGE_SIZE), (0x12000, PAGE_SIZE)]);
+
+        let mut buf = Vec::new();
+        snapshot.write_to(&mut buf).unwrap();
+        let loaded = Snapshot::read_from(&mut buf.as_slice()).unwrap();
+
+        memory.write(0x10010, b"after!").unwrap();
+        let restored = loaded.restore(&mut memory).unwrap();
+        assert_eq!(restored.rip(), 0x401000);
+        assert_eq!(restored.cr3(), 0x1000);
+        assert_eq!(&memory.as_slice()[0x10..0x16], b"before");
+        assert_eq!(loaded.idt()[14].idt_addr(), 0xdeadbeef);
+        assert_eq!(loaded.tss().unwrap().tss_ist(1), 0x9000);
+        assert!(loaded.vcpu().is_none());
+    }
+
+    fn pages_section(start: u64, pages: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&SNAPSHOT_MAGIC);
+        buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        write_section(&mut buf, TAG_REGS, pod_bytes(&DuneConfig::default())).unwrap();
+        let mut payload = start.to_le_bytes().to_vec();
+        payload.resize(8 + pages * PAGE_SIZE as usize, 0);
+        write_section(&mut buf, TAG_PAGES, &payload).unwrap();
+        buf
+    }
+
+    #[test]
+    fn rejects_bad_ranges_and_conflicting_mappings() {
+        assert!(Snapshot::read_from(&mut pages_section(0x10000, 1).as_slice()).is_ok());
+        for start in [0x10001, !(PAGE_SIZE - 1), u64::MAX - PAGE_SIZE] {
+            let err = Snapshot::read_from(&mut pages_section(start, 1).as_slice()).unwrap_err();
+            assert!(matches!(err, Error::InvalidInput(_)), "{:#x}: {}", start, err);
+        }
+
+        /* a page of this process's own stack is already mapped */
+        let local = 0u64;
+        let page = &local as *const u64 as u64 & !(PAGE_SIZE - 1);
+        let snapshot = Snapshot::read_from(&mut pages_section(page, 1).as_slice()).unwrap();
+        let err = snapshot.map_host_pages().unwrap_err();
+        assert!(matches!(err, Error::Memory { errno: Errno::EEXIST, .. }));
+    }
+}
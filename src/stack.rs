@@ -0,0 +1,264 @@
+use crate::backend::PAGE_SIZE;
+use crate::dune::DuneLayout;
+use crate::mem::GuestMemory;
+use crate::{Error, Result};
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_SYSINFO_EHDR: u64 = 33;
+
+/// A finished initial stack: `bytes` belong at `[rsp, top)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitialStack {
+    pub rsp: u64,
+    pub top: u64,
+    pub bytes: Vec<u8>,
+    pub argv: Vec<u64>,
+    pub envp: Vec<u64>,
+    pub random: u64,
+}
+
+impl InitialStack {
+    pub fn write_to(&self, memory: &mut dyn GuestMemory) -> Result<u64> {
+        memory.write(self.rsp, &self.bytes)?;
+        Ok(self.rsp)
+    }
+}
+
+/// Builds the SysV x86-64 process entry stack without touching guest memory.
+///
+/// From `rsp` upwards the result holds argc, the argv pointers, NULL, the
+/// envp pointers, NULL and the auxv pairs ending in `AT_NULL`; above them
+/// sit the strings and the 16 `AT_RANDOM` bytes. `rsp` is 16-byte aligned.
+#[derive(Debug, Clone)]
+pub struct StackBuilder {
+    top: u64,
+    argv: Vec<Vec<u8>>,
+    envp: Vec<Vec<u8>>,
+    auxv: Vec<(u64, u64)>,
+    random: [u8; 16],
+}
+
+impl StackBuilder {
+    pub fn new(top: u64) -> Self {
+        Self {
+            top: top & !0xf,
+            argv: Vec::new(),
+            envp: Vec::new(),
+            auxv: vec![(AT_PAGESZ, PAGE_SIZE)],
+            random: [0; 16],
+        }
+    }
+
+    /// Start one guard page below the Dune stack region.
+    pub fn near_base_stack(layout: &DuneLayout) -> Result<Self> {
+        let top = layout.base_stack().as_u64().checked_sub(PAGE_SIZE).ok_or_else(|| {
+            Error::InvalidInput("Dune stack region starts in the first page".to_string())
+        })?;
+        Ok(Self::new(top))
+    }
+
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    pub fn arg<S: AsRef<[u8]>>(&mut self, arg: S) -> &mut Self {
+        self.argv.push(arg.as_ref().to_vec());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    pub fn env<S: AsRef<[u8]>>(&mut self, var: S) -> &mut Self {
+        self.envp.push(var.as_ref().to_vec());
+        self
+    }
+
+    pub fn envs<I, S>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        for var in vars {
+            self.env(var);
+        }
+        self
+    }
+
+    /// Add or replace an auxv entry. `AT_RANDOM` and `AT_NULL` are filled in by `build`.
+    pub fn aux(&mut self, key: u64, value: u64) -> &mut Self {
+        match self.auxv.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.auxv.push((key, value)),
+        }
+        self
+    }
+
+    pub fn phdr(&mut self, addr: u64, entsize: u64, count: u64) -> &mut Self {
+        self.aux(AT_PHDR, addr).aux(AT_PHENT, entsize).aux(AT_PHNUM, count)
+    }
+
+    pub fn entry(&mut self, entry: u64) -> &mut Self {
+        self.aux(AT_ENTRY, entry)
+    }
+
+    /// Address of a vDSO-style image the guest can find through `AT_SYSINFO_EHDR`.
+    pub fn sysinfo_ehdr(&mut self, addr: u64) -> &mut Self {
+        self.aux(AT_SYSINFO_EHDR, addr)
+    }
+
+    /// Bytes `AT_RANDOM` points at; all zero unless set.
+    pub fn random(&mut self, bytes: [u8; 16]) -> &mut Self {
+        self.random = bytes;
+        self
+    }
+
+    /// Lay out the stack; fails if it does not fit below `top`.
+    pub fn build(&self) -> Result<InitialStack> {
+        let overflow =
+            || Error::InvalidInput(format!("initial stack does not fit below {:#x}", self.top));
+        let random = self.top.checked_sub(16).ok_or_else(overflow)?;
+        let mut sp = random;
+        let mut strings = |list: &[Vec<u8>]| -> Option<Vec<u64>> {
+            list.iter()
+                .map(|s| {
+                    sp = sp.checked_sub(s.len() as u64 + 1)?;
+                    Some(sp)
+                })
+                .collect()
+        };
+        let envp = strings(&self.envp).ok_or_else(overflow)?;
+        let argv = strings(&self.argv).ok_or_else(overflow)?;
+        let strings_start = sp;
+
+        let mut words = vec![self.argv.len() as u64];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for &(key, value) in &self.auxv {
+            words.extend([key, value]);
+        }
+        words.extend([AT_RANDOM, random, AT_NULL, 0]);
+
+        let rsp = strings_start
+            .checked_sub(words.len() as u64 * 8)
+            .ok_or_else(overflow)?
+            & !0xf;
+        let mut bytes = vec![0; (self.top - rsp) as usize];
+        let mut put = |addr: u64, data: &[u8]| {
+            let off = (addr - rsp) as usize;
+            bytes[off..off + data.len()].copy_from_slice(data);
+        };
+        for (i, word) in words.iter().enumerate() {
+            put(rsp + i as u64 * 8, &word.to_le_bytes());
+        }
+        for (addr, s) in argv.iter().zip(&self.argv).chain(envp.iter().zip(&self.envp)) {
+            put(*addr, s);
+        }
+        put(random, &self.random);
+
+        Ok(InitialStack {
+            rsp,
+            top: self.top,
+            bytes,
+            argv,
+            envp,
+            random,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BufferMemory;
+
+    fn auxv(memory: &BufferMemory, stack: &InitialStack) -> Vec<(u64, u64)> {
+        let argc = memory.read_u64(stack.rsp).unwrap();
+        let mut addr = stack.rsp + 8 * (argc + 2 + stack.envp.len() as u64 + 1);
+        let mut entries = Vec::new();
+        loop {
+            let key = memory.read_u64(addr).unwrap();
+            entries.push((key, memory.read_u64(addr + 8).unwrap()));
+            if key == AT_NULL {
+                return entries;
+            }
+            addr += 16;
+        }
+    }
+
+    #[test]
+    fn sysv_layout() {
+        let stack = StackBuilder::new(0x8000)
+            .args(["/bin/guest", "a", "bc"])
+            .env("PATH=/bin")
+            .phdr(0x400040, 56, 4)
+            .entry(0x401000)
+            .sysinfo_ehdr(0x7000_0000)
+            .random([7; 16])
+            .build()
+            .unwrap();
+
+        let mut memory = BufferMemory::new(0x7000, 0x1000);
+        assert_eq!(stack.write_to(&mut memory).unwrap() % 16, 0);
+        assert_eq!(memory.read_u64(stack.rsp).unwrap(), 3);
+        assert_eq!(memory.read_u64(stack.rsp + 8).unwrap(), stack.argv[0]);
+        assert_eq!(memory.read_u64(stack.rsp + 32).unwrap(), 0);
+        assert_eq!(memory.read_u64(stack.rsp + 40).unwrap(), stack.envp[0]);
+
+        let mut arg = [0; 3];
+        memory.read(stack.argv[2], &mut arg).unwrap();
+        assert_eq!(&arg, b"bc\0");
+        let mut random = [0; 16];
+        memory.read(stack.random, &mut random).unwrap();
+        assert_eq!(random, [7; 16]);
+
+        assert_eq!(
+            auxv(&memory, &stack),
+            vec![
+                (AT_PAGESZ, PAGE_SIZE),
+                (AT_PHDR, 0x400040),
+                (AT_PHENT, 56),
+                (AT_PHNUM, 4),
+                (AT_ENTRY, 0x401000),
+                (AT_SYSINFO_EHDR, 0x7000_0000),
+                (AT_RANDOM, stack.random),
+                (AT_NULL, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn aligned_for_any_argument_count() {
+        for n in 0..4 {
+            let stack = StackBuilder::new(0x10009).args(vec!["x"; n]).build().unwrap();
+            assert_eq!(stack.top, 0x10000);
+            assert_eq!(stack.rsp % 16, 0);
+            assert_eq!(stack.bytes.len() as u64, stack.top - stack.rsp);
+            assert_eq!(u64::from_le_bytes(stack.bytes[..8].try_into().unwrap()), n as u64);
+        }
+    }
+
+    #[test]
+    fn rejects_stacks_that_do_not_fit() {
+        assert!(StackBuilder::new(0x10).build().is_err());
+        assert!(StackBuilder::new(0x100).arg([b'a'; 0x100]).build().is_err());
+        assert!(StackBuilder::new(0x400).args(vec!["x"; 0x100]).build().is_err());
+        assert!(StackBuilder::near_base_stack(&DuneLayout::default()).is_err());
+    }
+}
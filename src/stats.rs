@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+use crate::dune::{DuneConfig, DuneRetCode};
+use crate::instrument::vcpu_span;
+use crate::Result;
+
+/// Upper bounds of the exit-latency histogram buckets, in nanoseconds.
+pub const LATENCY_BUCKETS_NS: [u64; 10] = [
+    1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 500_000, 1_000_000, 10_000_000,
+];
+
+const RET_CODES: [DuneRetCode; 9] = [
+    DuneRetCode::None,
+    DuneRetCode::Exit,
+    DuneRetCode::Syscall,
+    DuneRetCode::Interrupt,
+    DuneRetCode::Signal,
+    DuneRetCode::EptViolation,
+    DuneRetCode::NoEnter,
+    DuneRetCode::UnhandledVmexit,
+    DuneRetCode::Unknown,
+];
+
+fn ret_index(ret: DuneRetCode) -> usize {
+    RET_CODES.iter().position(|code| *code == ret).unwrap()
+}
+
+/// Latency histogram with fixed buckets; the last count is the overflow bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub counts: [u64; LATENCY_BUCKETS_NS.len() + 1],
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ns = latency.as_nanos() as u64;
+        let bucket = LATENCY_BUCKETS_NS
+            .iter()
+            .position(|bound| ns <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_NS.len());
+        self.counts[bucket] += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// A point-in-time copy of one vCPU's counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub vcpu: u32,
+    pub exits: Vec<(DuneRetCode, u64)>,
+    pub syscalls: BTreeMap<u64, u64>,
+    pub guest_time: Duration,
+    pub host_time: Duration,
+    pub latency: LatencyHistogram,
+}
+
+impl StatsSnapshot {
+    pub fn exits(&self, ret: DuneRetCode) -> u64 {
+        self.exits
+            .iter()
+            .find(|(code, _)| *code == ret)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    pub fn total_exits(&self) -> u64 {
+        self.exits.iter().map(|(_, count)| count).sum()
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        prometheus(std::slice::from_ref(self))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    exits: [u64; RET_CODES.len()],
+    syscalls: BTreeMap<u64, u64>,
+    guest_time: Duration,
+    host_time: Duration,
+    latency: LatencyHistogram,
+    entered: Option<Instant>,
+    exited: Option<Instant>,
+}
+
+/// Exit counters and guest/host time for one vCPU.
+///
+/// The vCPU thread brackets each entry with `begin_entry`/`end_entry` (or
+/// uses `enter`); any thread may take a `stats()` snapshot meanwhile.
+/// Host time and exit latency are measured from an exit to the next entry.
+#[derive(Debug, Default)]
+pub struct VcpuStats {
+    vcpu: u32,
+    counters: Mutex<Counters>,
+}
+
+impl VcpuStats {
+    pub fn new(vcpu: u32) -> Self {
+        Self {
+            vcpu,
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    pub fn vcpu(&self) -> u32 {
+        self.vcpu
+    }
+
+    /// Record the time since the last exit as host time, then start the guest clock.
+    pub fn begin_entry(&self) {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(exited) = counters.exited.take() {
+            counters.record_host(now - exited);
+        }
+        counters.entered = Some(now);
+    }
+
+    /// Stop the guest clock and count the exit in `conf`.
+    pub fn end_entry(&self, conf: &DuneConfig) {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        let guest = counters.entered.take().map_or(Duration::ZERO, |entered| now - entered);
+        counters.record_exit(conf, guest);
+        counters.exited = Some(now);
+    }
+
+    /// Enter the guest through `backend`, accounting the round trip.
+    pub fn enter(&self, backend: &dyn Backend, conf: &mut DuneConfig) -> Result<()> {
+        let _span = vcpu_span(self.vcpu);
+        self.begin_entry();
+        let result = backend.enter(conf);
+        match result {
+            Ok(()) => self.end_entry(conf),
+            Err(_) => self.cancel_entry(),
+        }
+        result
+    }
+
+    /// Forget an entry that never reached the guest, so no exit is counted.
+    pub fn cancel_entry(&self) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.entered = None;
+        counters.exited = Some(Instant::now());
+    }
+
+    /// Count an exit that spent `guest` in the guest, without timing it here.
+    pub fn record_exit(&self, conf: &DuneConfig, guest: Duration) {
+        self.counters.lock().unwrap().record_exit(conf, guest);
+    }
+
+    /// Count `latency` of host-side exit handling, without timing it here.
+    pub fn record_host(&self, latency: Duration) {
+        self.counters.lock().unwrap().record_host(latency);
+    }
+
+    pub fn reset(&self) {
+        *self.counters.lock().unwrap() = Counters::default();
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        let counters = self.counters.lock().unwrap();
+        StatsSnapshot {
+            vcpu: self.vcpu,
+            exits: RET_CODES
+                .iter()
+                .zip(counters.exits)
+                .filter(|(_, count)| *count != 0)
+                .map(|(code, count)| (*code, count))
+                .collect(),
+            syscalls: counters.syscalls.clone(),
+            guest_time: counters.guest_time,
+            host_time: counters.host_time,
+            latency: counters.latency.clone(),
+        }
+    }
+}
+
+impl Counters {
+    fn record_exit(&mut self, conf: &DuneConfig, guest: Duration) {
+        let ret = DuneRetCode::from(conf.ret());
+        self.exits[ret_index(ret)] += 1;
+        if ret == DuneRetCode::Syscall {
+            *self.syscalls.entry(conf.rax() as u64).or_default() += 1;
+        }
+        self.guest_time += guest;
+    }
+
+    fn record_host(&mut self, latency: Duration) {
+        self.host_time += latency;
+        self.latency.record(latency);
+    }
+}
+
+/// Stats for every vCPU of a guest, exported together.
+#[derive(Debug, Default)]
+pub struct StatsRegistry {
+    vcpus: Mutex<Vec<Arc<VcpuStats>>>,
+}
+
+impl StatsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, vcpu: u32) -> Arc<VcpuStats> {
+        let stats = Arc::new(VcpuStats::new(vcpu));
+        self.vcpus.lock().unwrap().push(stats.clone());
+        stats
+    }
+
+    pub fn stats(&self) -> Vec<StatsSnapshot> {
+        self.vcpus.lock().unwrap().iter().map(|vcpu| vcpu.stats()).collect()
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        prometheus(&self.stats())
+    }
+}
+
+/// Render snapshots in the Prometheus text exposition format.
+pub fn prometheus(snapshots: &[StatsSnapshot]) -> String {
+    let mut out = String::new();
+    let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    };
+
+    header(&mut out, "dune_vcpu_exits_total", "counter", "Guest exits by return code.");
+    for s in snapshots {
+        for (code, count) in &s.exits {
+            let _ = writeln!(
+                out,
+                "dune_vcpu_exits_total{{vcpu=\"{}\",reason=\"{}\"}} {}",
+                s.vcpu,
+                code.name(),
+                count
+            );
+        }
+    }
+
+    header(&mut out, "dune_vcpu_syscalls_total", "counter", "Syscall exits by syscall number.");
+    for s in snapshots {
+        for (nr, count) in &s.syscalls {
+            let _ = writeln!(out, "dune_vcpu_syscalls_total{{vcpu=\"{}\",nr=\"{}\"}} {}", s.vcpu, nr, count);
+        }
+    }
+
+    header(&mut out, "dune_vcpu_guest_seconds_total", "counter", "Time spent in guest mode.");
+    for s in snapshots {
+        let _ = writeln!(
+            out,
+            "dune_vcpu_guest_seconds_total{{vcpu=\"{}\"}} {}",
+            s.vcpu,
+            s.guest_time.as_secs_f64()
+        );
+    }
+
+    header(&mut out, "dune_vcpu_host_seconds_total", "counter", "Time spent handling exits on the host.");
+    for s in snapshots {
+        let _ = writeln!(
+            out,
+            "dune_vcpu_host_seconds_total{{vcpu=\"{}\"}} {}",
+            s.vcpu,
+            s.host_time.as_secs_f64()
+        );
+    }
+
+    let name = "dune_vcpu_exit_latency_seconds";
+    header(&mut out, name, "histogram", "Time from a guest exit to the next entry.");
+    for s in snapshots {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_NS.iter().zip(&s.latency.counts) {
+            cumulative += count;
+            let le = *bound as f64 / 1e9;
+            let _ = writeln!(out, "{}_bucket{{vcpu=\"{}\",le=\"{}\"}} {}", name, s.vcpu, le, cumulative);
+        }
+        let count = s.latency.count();
+        let _ = writeln!(out, "{}_bucket{{vcpu=\"{}\",le=\"+Inf\"}} {}", name, s.vcpu, count);
+        let _ = writeln!(out, "{}_sum{{vcpu=\"{}\"}} {}", name, s.vcpu, s.latency.sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count{{vcpu=\"{}\"}} {}", name, s.vcpu, count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Layout, MockBackend};
+    use crate::probe::BackendKind;
+    use crate::vmpl::GetPages;
+    use crate::Error;
+    use nix::errno::Errno;
+    use x86_64::VirtAddr;
+    use crate::dune::{DUNE_RET_EXIT, DUNE_RET_SYSCALL};
+
+    #[test]
+    fn counts_exits_and_syscalls() {
+        let stats = VcpuStats::new(0);
+        let mut conf = DuneConfig::default();
+        conf.set_ret(DUNE_RET_SYSCALL).set_rax(libc::SYS_write);
+        stats.record_exit(&conf, Duration::from_micros(30));
+        stats.record_exit(&conf, Duration::from_micros(10));
+        stats.record_host(Duration::from_micros(3));
+        stats.record_host(Duration::from_millis(50));
+
+        let backend = MockBackend::new();
+        stats.enter(&backend, &mut conf).unwrap();
+        assert_eq!(conf.ret(), DUNE_RET_EXIT);
+
+        let snap = stats.stats();
+        assert_eq!(snap.exits(DuneRetCode::Syscall), 2);
+        assert_eq!(snap.exits(DuneRetCode::Exit), 1);
+        assert_eq!(snap.total_exits(), 3);
+        assert_eq!(snap.syscalls[&(libc::SYS_write as u64)], 2);
+        assert!(snap.guest_time >= Duration::from_micros(40));
+        assert_eq!(snap.latency.counts[2], 1);
+        assert_eq!(snap.latency.counts[LATENCY_BUCKETS_NS.len()], 1);
+    }
+
+    struct FailingBackend;
+
+    impl Backend for FailingBackend {
+        fn kind(&self) -> BackendKind {
+            BackendKind::Dune
+        }
+
+        fn layout(&self) -> Result<Layout> {
+            unreachable!()
+        }
+
+        fn enter(&self, _config: &mut DuneConfig) -> Result<()> {
+            Err(Error::Ioctl { name: "DUNE_ENTER", errno: Errno::EINTR })
+        }
+
+        fn set_page_perm(&self, _va: VirtAddr, _nr_pages: u32, _perm: u32) -> Result<()> {
+            unreachable!()
+        }
+
+        fn alloc_pages(&self, _nr_pages: u64) -> Result<GetPages> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn failed_entry_is_not_an_exit() {
+        let stats = VcpuStats::new(0);
+        let mut conf = DuneConfig::default();
+        assert!(stats.enter(&FailingBackend, &mut conf).is_err());
+        let snap = stats.stats();
+        assert_eq!(snap.total_exits(), 0);
+        assert_eq!(snap.latency.count(), 0);
+        assert_eq!(snap.guest_time, Duration::ZERO);
+
+        stats.enter(&MockBackend::new(), &mut conf).unwrap();
+        assert_eq!(stats.stats().exits(DuneRetCode::Exit), 1);
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let registry = StatsRegistry::new();
+        let vcpu = registry.register(1);
+        let mut conf = DuneConfig::default();
+        conf.set_ret(DUNE_RET_SYSCALL).set_rax(39);
+        vcpu.record_exit(&conf, Duration::from_millis(2));
+        vcpu.record_host(Duration::from_micros(1));
+
+        let text = registry.to_prometheus();
+        assert!(text.contains("dune_vcpu_exits_total{vcpu=\"1\",reason=\"syscall\"} 1\n"));
+        assert!(text.contains("dune_vcpu_syscalls_total{vcpu=\"1\",nr=\"39\"} 1\n"));
+        assert!(text.contains("dune_vcpu_guest_seconds_total{vcpu=\"1\"} 0.002\n"));
+        assert!(text.contains("dune_vcpu_exit_latency_seconds_bucket{vcpu=\"1\",le=\"0.000001\"} 1\n"));
+        assert!(text.contains("dune_vcpu_exit_latency_seconds_count{vcpu=\"1\"} 1\n"));
+        assert_eq!(text.matches("# TYPE").count(), 5);
+    }
+}
@@ -0,0 +1,373 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use nix::errno::Errno;
+
+use crate::dune::DuneConfig;
+use crate::signal::{vector_signal, SignalManager};
+use crate::{Error, Result};
+
+/// How the host produces ticks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimerMode {
+    /// `setitimer(ITIMER_REAL)`: one process-wide timer, delivered to any thread.
+    Itimer,
+    /// A helper thread reads a `timerfd` and signals the vCPU thread directly,
+    /// so each vCPU can have its own slice.
+    Timerfd,
+}
+
+/// What the run loop should do after a tick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TickAction {
+    /// Re-enter the guest where it left off.
+    Continue,
+    /// The slice is over; switch to another context.
+    Preempt,
+    /// Stop running this guest, e.g. because its quota is used up.
+    Stop,
+}
+
+/// Passed to the tick callback.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tick {
+    pub count: u64,
+    pub slice: Duration,
+    pub used: Duration,
+    pub quota: Option<Duration>,
+}
+
+impl Tick {
+    pub fn remaining(&self) -> Option<Duration> {
+        self.quota.map(|quota| quota.saturating_sub(self.used))
+    }
+}
+
+fn timeval(d: Duration) -> libc::timeval {
+    libc::timeval {
+        tv_sec: d.as_secs() as libc::time_t,
+        tv_usec: d.subsec_micros() as libc::suseconds_t,
+    }
+}
+
+fn timespec(d: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: d.as_secs() as libc::time_t,
+        tv_nsec: d.subsec_nanos() as libc::c_long,
+    }
+}
+
+extern "C" fn tick_handler(_signo: libc::c_int) {
+    /* only here so the signal interrupts guest mode instead of killing us */
+}
+
+fn install_tick_handler(signo: i32) -> Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = tick_handler as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        /* no SA_RESTART: the pending signal has to end the current entry */
+        if libc::sigaction(signo, &action, std::ptr::null_mut()) < 0 {
+            return Err(Error::last_syscall("sigaction"));
+        }
+    }
+    Ok(())
+}
+
+struct TimerfdThread {
+    fd: libc::c_int,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl TimerfdThread {
+    fn spawn(slice: Duration, signo: i32) -> Result<Self> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_syscall("timerfd_create"));
+        }
+        let spec = libc::itimerspec {
+            it_interval: timespec(slice),
+            it_value: timespec(slice),
+        };
+        if unsafe { libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) } < 0 {
+            let errno = Errno::last();
+            unsafe { libc::close(fd) };
+            return Err(Error::LibcError(errno));
+        }
+
+        let pid = unsafe { libc::getpid() };
+        let tid = unsafe { libc::gettid() };
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut expirations = 0u64;
+                while !stop.load(Ordering::Acquire) {
+                    let ret = unsafe { libc::read(fd, &mut expirations as *mut u64 as *mut _, 8) };
+                    if ret != 8 || stop.load(Ordering::Acquire) {
+                        continue;
+                    }
+                    unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signo) };
+                }
+            })
+        };
+        Ok(Self { fd, stop, thread })
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        /* the thread wakes up on the next expiration and sees the flag */
+        let _ = self.thread.join();
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Forces periodic exits out of guest mode and accounts CPU time per slice.
+///
+/// The host timer raises `signo` (SIGALRM by default) on the vCPU thread.
+/// Under Dune the signal either ends the entry with `DUNE_RET_SIGNAL` or is
+/// reflected into the guest on vector `DUNE_SIGNAL_INTR_BASE + signo`; feed
+/// the former to `handle_exit` and the latter to `handle_vector`.
+pub struct Preemption {
+    slice: Duration,
+    mode: TimerMode,
+    signo: i32,
+    quota: Option<Duration>,
+    on_tick: Box<dyn FnMut(&Tick) -> TickAction + Send>,
+    used: Duration,
+    ticks: u64,
+    timerfd: Option<TimerfdThread>,
+    armed: bool,
+}
+
+impl Preemption {
+    pub fn new(slice: Duration) -> Self {
+        Self {
+            slice,
+            mode: TimerMode::Itimer,
+            signo: libc::SIGALRM,
+            quota: None,
+            on_tick: Box::new(|_| TickAction::Preempt),
+            used: Duration::ZERO,
+            ticks: 0,
+            timerfd: None,
+            armed: false,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: TimerMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_signal(mut self, signo: i32) -> Self {
+        self.signo = signo;
+        self
+    }
+
+    /// Stop the guest once it has run for `quota` worth of slices.
+    pub fn with_quota(mut self, quota: Duration) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    pub fn on_tick<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Tick) -> TickAction + Send + 'static,
+    {
+        self.on_tick = Box::new(f);
+        self
+    }
+
+    pub fn slice(&self) -> Duration {
+        self.slice
+    }
+
+    pub fn signal(&self) -> i32 {
+        self.signo
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn used(&self) -> Duration {
+        self.used
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Give the guest a fresh quota, e.g. at the start of an accounting period.
+    pub fn reset_quota(&mut self) {
+        self.used = Duration::ZERO;
+    }
+
+    /// Install the host handler and arm the timer.
+    ///
+    /// With `TimerMode::Timerfd` the calling thread is the one signalled, so
+    /// call this from the thread that enters the guest.
+    pub fn start(&mut self) -> Result<()> {
+        if self.armed {
+            return Err(Error::AlreadyExists);
+        }
+        if self.slice.is_zero() {
+            return Err(Error::InvalidInput("Time slice must not be zero".to_string()));
+        }
+        if self.mode == TimerMode::Itimer && self.signo != libc::SIGALRM {
+            return Err(Error::InvalidInput("setitimer only raises SIGALRM".to_string()));
+        }
+        install_tick_handler(self.signo)?;
+        match self.mode {
+            TimerMode::Itimer => {
+                let timer = libc::itimerval {
+                    it_interval: timeval(self.slice),
+                    it_value: timeval(self.slice),
+                };
+                if unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut()) } < 0 {
+                    return Err(Error::last_syscall("setitimer"));
+                }
+            }
+            TimerMode::Timerfd => self.timerfd = Some(TimerfdThread::spawn(self.slice, self.signo)?),
+        }
+        self.armed = true;
+        Ok(())
+    }
+
+    /// Disarm the timer. Ticks already raised may still show up as exits.
+    pub fn stop(&mut self) -> Result<()> {
+        if !self.armed {
+            return Ok(());
+        }
+        self.armed = false;
+        match self.mode {
+            TimerMode::Itimer => {
+                let timer: libc::itimerval = unsafe { std::mem::zeroed() };
+                if unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut()) } < 0 {
+                    return Err(Error::last_syscall("setitimer"));
+                }
+            }
+            TimerMode::Timerfd => {
+                if let Some(timerfd) = self.timerfd.take() {
+                    timerfd.stop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Account one slice and ask the callback what to do next.
+    pub fn tick(&mut self) -> TickAction {
+        self.ticks += 1;
+        self.used += self.slice;
+        let tick = Tick {
+            count: self.ticks,
+            slice: self.slice,
+            used: self.used,
+            quota: self.quota,
+        };
+        let action = (self.on_tick)(&tick);
+        match tick.remaining() {
+            Some(remaining) if remaining.is_zero() => TickAction::Stop,
+            _ => action,
+        }
+    }
+
+    /// Handle a `DUNE_RET_SIGNAL` exit; `None` if it was not our tick.
+    pub fn handle_exit(&mut self, conf: &DuneConfig) -> Option<TickAction> {
+        match SignalManager::exit_signal(conf) {
+            Some(signo) if signo == self.signo => Some(self.tick()),
+            _ => None,
+        }
+    }
+
+    /// Handle an interrupt taken in the guest; `None` if it was not our tick.
+    pub fn handle_vector(&mut self, vector: u8) -> Option<TickAction> {
+        match vector_signal(vector) {
+            Some(signo) if signo == self.signo => Some(self.tick()),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Preemption {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dune::{DUNE_RET_SIGNAL, DUNE_RET_SYSCALL};
+    use crate::signal::signal_vector;
+
+    #[test]
+    fn quota_stops_guest() {
+        let mut preempt = Preemption::new(Duration::from_millis(10))
+            .with_quota(Duration::from_millis(25))
+            .on_tick(|tick| {
+                if tick.count == 1 {
+                    TickAction::Continue
+                } else {
+                    TickAction::Preempt
+                }
+            });
+
+        let mut conf = DuneConfig::default();
+        conf.set_ret(DUNE_RET_SYSCALL);
+        assert_eq!(preempt.handle_exit(&conf), None);
+        conf.set_ret(DUNE_RET_SIGNAL).set_status(libc::SIGALRM as i64);
+        assert_eq!(preempt.handle_exit(&conf), Some(TickAction::Continue));
+        assert_eq!(preempt.handle_vector(signal_vector(libc::SIGALRM).unwrap()), Some(TickAction::Preempt));
+        assert_eq!(preempt.handle_vector(14), None);
+        assert_eq!(preempt.tick(), TickAction::Stop);
+        assert_eq!(preempt.used(), Duration::from_millis(30));
+
+        preempt.reset_quota();
+        assert_eq!(preempt.tick(), TickAction::Preempt);
+    }
+
+    #[test]
+    fn timerfd_signals_this_thread() {
+        let mut preempt = Preemption::new(Duration::from_millis(5))
+            .with_mode(TimerMode::Timerfd)
+            .with_signal(libc::SIGUSR2);
+        preempt.start().unwrap();
+        assert!(preempt.is_armed());
+        assert!(matches!(preempt.start(), Err(Error::AlreadyExists)));
+
+        /* a tick shows up as an interrupted sleep on this thread */
+        let nap = timespec(Duration::from_millis(50));
+        let interrupted = (0..40).any(|_| {
+            let ret = unsafe { libc::nanosleep(&nap, std::ptr::null_mut()) };
+            ret < 0 && Errno::last() == Errno::EINTR
+        });
+        assert!(interrupted);
+
+        let started = std::time::Instant::now();
+        preempt.stop().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(!preempt.is_armed());
+        preempt.stop().unwrap();
+    }
+
+    #[test]
+    fn itimer_start_and_stop() {
+        let mut preempt = Preemption::new(Duration::from_secs(60)).with_signal(libc::SIGUSR2);
+        assert!(matches!(preempt.start(), Err(Error::InvalidInput(_))));
+
+        /* the slice is long enough that SIGALRM never fires */
+        let mut preempt = Preemption::new(Duration::from_secs(60));
+        preempt.start().unwrap();
+        assert!(preempt.is_armed());
+        preempt.stop().unwrap();
+        let mut timer: libc::itimerval = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::getitimer(libc::ITIMER_REAL, &mut timer) }, 0);
+        assert_eq!((timer.it_value.tv_sec, timer.it_value.tv_usec), (0, 0));
+    }
+}
@@ -0,0 +1,193 @@
+use std::ffi::c_void;
+use std::mem::size_of;
+
+use libc::c_int;
+
+use crate::debug::{dune_trap_resume, DuneTrapConfig, DuneTrapRegs};
+use crate::dev::{dune_trap_disable, dune_trap_enable, Device};
+use crate::instrument::ioctl_event;
+use crate::ioctl_error::TrapEnableError;
+use crate::{Error, Result};
+
+type TraceHandler = Box<dyn FnMut(&mut DuneTrapRegs) + Send>;
+
+/// A trap on `trigger_rip` that runs a Rust closure with the trapped registers.
+///
+/// The tracepoint owns the register buffer and the closure handed to the
+/// kernel, so both stay valid for as long as the trap is armed.
+pub struct Tracepoint {
+    trigger_rip: u64,
+    delay: u8,
+    regs: Box<DuneTrapRegs>,
+    handler: Box<TraceHandler>,
+}
+
+impl Tracepoint {
+    pub fn at<F>(rip: u64, handler: F) -> Self
+    where
+        F: FnMut(&mut DuneTrapRegs) + Send + 'static,
+    {
+        Self {
+            trigger_rip: rip,
+            delay: 0,
+            regs: Box::default(),
+            handler: Box::new(Box::new(handler)),
+        }
+    }
+
+    pub fn with_delay(mut self, delay: u8) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn trigger_rip(&self) -> u64 {
+        self.trigger_rip
+    }
+
+    pub fn delay(&self) -> u8 {
+        self.delay
+    }
+
+    /// Registers captured on the last hit.
+    pub fn regs(&self) -> &DuneTrapRegs {
+        &self.regs
+    }
+
+    fn config(&mut self) -> DuneTrapConfig {
+        let mut config = DuneTrapConfig::default();
+        config
+            .set_trigger_rip(self.trigger_rip)
+            .set_notify_func(tracepoint_notify)
+            .set_regs(&mut *self.regs)
+            .set_regs_size(size_of::<DuneTrapRegs>() as u64)
+            .set_priv_data(&mut *self.handler as *mut TraceHandler as *mut c_void)
+            .set_delay(self.delay);
+        config
+    }
+
+    /// Arm the trap on `device`; it is disarmed when the guard is dropped.
+    pub fn enable<D: Device>(&mut self, device: &D) -> Result<TracepointGuard<'_>> {
+        let fd = device.fd();
+        let mut config = self.config();
+        let result = unsafe { dune_trap_enable(fd, &mut config) };
+        ioctl_event!("DUNE_TRAP_ENABLE", fd, result, trigger_rip = self.trigger_rip, delay = self.delay);
+        result.map_err(TrapEnableError::from)?;
+        Ok(TracepointGuard {
+            fd,
+            tracepoint: self,
+            armed: true,
+        })
+    }
+}
+
+pub struct TracepointGuard<'a> {
+    fd: c_int,
+    tracepoint: &'a mut Tracepoint,
+    armed: bool,
+}
+
+impl TracepointGuard<'_> {
+    pub fn tracepoint(&self) -> &Tracepoint {
+        self.tracepoint
+    }
+
+    /// Disarm the trap, reporting any failure instead of ignoring it.
+    pub fn disable(mut self) -> Result<()> {
+        self.armed = false;
+        let result = unsafe { dune_trap_disable(self.fd) };
+        ioctl_event!("DUNE_TRAP_DISABLE", self.fd, result);
+        result.map_err(Error::ioctl("DUNE_TRAP_DISABLE"))?;
+        Ok(())
+    }
+}
+
+impl Drop for TracepointGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let result = unsafe { dune_trap_disable(self.fd) };
+            ioctl_event!("DUNE_TRAP_DISABLE", self.fd, result);
+        }
+    }
+}
+
+extern "C" fn tracepoint_notify(regs: *mut DuneTrapRegs, priv_data: *mut c_void) -> ! {
+    unsafe {
+        let handler = &mut *(priv_data as *mut TraceHandler);
+        handler(&mut *regs);
+        dune_trap_resume(regs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno;
+
+    /// A device on /dev/null: every Dune ioctl fails with ENOTTY.
+    struct NullDevice {
+        fd: c_int,
+    }
+
+    impl Device for NullDevice {
+        fn fd(&self) -> c_int {
+            self.fd
+        }
+
+        fn open(&mut self, _path: &str) -> Result<i32> {
+            self.fd = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDWR) };
+            assert!(self.fd >= 0);
+            Ok(self.fd)
+        }
+
+        fn close(&self) -> Result<i32> {
+            unsafe { libc::close(self.fd) };
+            Ok(0)
+        }
+
+        fn ioctl<T>(&self, _request: u64, _arg: *mut T) -> Result<i32> {
+            Err(Error::LibcError(Errno::ENOTTY))
+        }
+    }
+
+    #[test]
+    fn config_points_at_owned_buffers() {
+        let mut tracepoint = Tracepoint::at(0x401000, |regs| {
+            regs.set_rax(1);
+        })
+        .with_delay(3);
+        let config = tracepoint.config();
+
+        assert_eq!((config.trigger_rip(), config.delay()), (0x401000, 3));
+        assert_eq!(config.regs() as *const DuneTrapRegs, &*tracepoint.regs as *const DuneTrapRegs);
+        assert_eq!(config.regs_size(), size_of::<DuneTrapRegs>() as u64);
+        assert_eq!(
+            config.priv_data() as *const TraceHandler,
+            &*tracepoint.handler as *const TraceHandler
+        );
+
+        /* priv_data is what the notify function calls */
+        let handler = unsafe { &mut *(config.priv_data() as *mut TraceHandler) };
+        handler(&mut tracepoint.regs);
+        assert_eq!(tracepoint.regs().rax(), 1);
+    }
+
+    #[test]
+    fn enable_and_disable_report_ioctl_errors() {
+        let mut device = NullDevice { fd: -1 };
+        device.open("/dev/null").unwrap();
+        let mut tracepoint = Tracepoint::at(0x401000, |_| {});
+
+        let err = tracepoint.enable(&device).err().unwrap();
+        assert!(matches!(err, Error::Ioctl { name: "DUNE_TRAP_ENABLE", errno: Errno::ENOTTY }));
+
+        let guard = TracepointGuard {
+            fd: device.fd(),
+            tracepoint: &mut tracepoint,
+            armed: true,
+        };
+        assert_eq!(guard.tracepoint().trigger_rip(), 0x401000);
+        let err = guard.disable().unwrap_err();
+        assert!(matches!(err, Error::Ioctl { name: "DUNE_TRAP_DISABLE", errno: Errno::ENOTTY }));
+        device.close().unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::debug::DuneTrapRegs;
+use crate::dev::Device;
+use crate::regs::{GeneralRegisters, Register, GENERAL_REGISTER_COUNT};
+use crate::tracepoint::{Tracepoint, TracepointGuard};
+use crate::{Error, Result};
+
+pub const RFLAGS_TF: u64 = 1 << 8;
+
+pub const TRACE_MAGIC: [u8; 4] = *b"DTRC";
+pub const TRACE_VERSION: u16 = 1;
+
+pub const TRACE_REG_COUNT: usize = GENERAL_REGISTER_COUNT;
+const TRACE_REG_RIP: usize = Register::Rip as usize;
+/// Every register but RIP, which each record stores anyway.
+const FULL_MASK: u32 = ((1 << TRACE_REG_COUNT) - 1) & !(1 << TRACE_REG_RIP);
+
+/// One executed instruction: its RIP and the registers that changed since the previous step.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    seq: u64,
+    changed: u32,
+    regs: [u64; TRACE_REG_COUNT],
+}
+
+impl TraceRecord {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn rip(&self) -> u64 {
+        self.regs[TRACE_REG_RIP]
+    }
+
+    /// Bitmask of changed registers, indexed by `Register::index` (RIP excluded).
+    pub fn changed(&self) -> u32 {
+        self.changed
+    }
+
+    pub fn deltas(&self) -> impl Iterator<Item = (Register, u64)> + '_ {
+        Register::ALL
+            .into_iter()
+            .filter(move |reg| self.changed & (1 << reg.index()) != 0)
+            .map(move |reg| (reg, self.regs[reg.index()]))
+    }
+
+    pub fn registers(&self) -> GeneralRegisters {
+        let mut regs = GeneralRegisters::new();
+        for reg in Register::ALL {
+            regs[reg] = self.regs[reg.index()];
+        }
+        regs
+    }
+}
+
+/// Fixed-size ring of trace records; the oldest entries are dropped first.
+#[derive(Debug, Clone)]
+pub struct TraceRing {
+    capacity: usize,
+    records: VecDeque<TraceRecord>,
+    prev: Option<[u64; TRACE_REG_COUNT]>,
+    total: u64,
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            records: VecDeque::with_capacity(capacity.max(1)),
+            prev: None,
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, regs: &DuneTrapRegs) {
+        let regs = GeneralRegisters::from(regs);
+        let regs = Register::ALL.map(|reg| regs[reg]);
+        let changed = match &self.prev {
+            Some(prev) => (0..TRACE_REG_COUNT)
+                .filter(|&idx| idx != TRACE_REG_RIP && prev[idx] != regs[idx])
+                .fold(0, |mask, idx| mask | (1 << idx)),
+            None => FULL_MASK,
+        };
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(TraceRecord {
+            seq: self.total,
+            changed,
+            regs,
+        });
+        self.prev = Some(regs);
+        self.total += 1;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Number of instructions recorded since the ring was created or last cleared.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.total - self.records.len() as u64
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.prev = None;
+        self.total = 0;
+    }
+
+    /// Write the compact binary form.
+    ///
+    /// Layout (little endian): magic, version, register count, record count,
+    /// first sequence number, then per record its RIP, the changed-register
+    /// mask and one value per set bit. The first record carries every
+    /// register, since the step it was diffed against may have been dropped.
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&TRACE_MAGIC)?;
+        w.write_all(&TRACE_VERSION.to_le_bytes())?;
+        w.write_all(&(TRACE_REG_COUNT as u16).to_le_bytes())?;
+        w.write_all(&(self.records.len() as u64).to_le_bytes())?;
+        w.write_all(&self.records.front().map_or(0, |r| r.seq).to_le_bytes())?;
+
+        for (i, record) in self.records.iter().enumerate() {
+            let changed = if i == 0 { FULL_MASK } else { record.changed };
+            w.write_all(&record.rip().to_le_bytes())?;
+            w.write_all(&changed.to_le_bytes())?;
+            for (idx, value) in record.regs.iter().enumerate() {
+                if changed & (1 << idx) != 0 {
+                    w.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a trace produced by `write_binary`.
+    pub fn read_binary<R: Read>(r: &mut R) -> Result<Vec<TraceRecord>> {
+        fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        if header[..4] != TRACE_MAGIC {
+            return Err(Error::InvalidInput("Not a trace file".to_string()));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let reg_count = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if version != TRACE_VERSION || reg_count != TRACE_REG_COUNT {
+            return Err(Error::InvalidInput(format!(
+                "Unsupported trace version {} with {} registers",
+                version, reg_count
+            )));
+        }
+
+        let count = read_u64(r)?;
+        let first = read_u64(r)?;
+        let mut records = Vec::new();
+        let mut regs = [0; TRACE_REG_COUNT];
+        for seq in first..first + count {
+            regs[TRACE_REG_RIP] = read_u64(r)?;
+            let mut mask = [0; 4];
+            r.read_exact(&mut mask)?;
+            let changed = u32::from_le_bytes(mask);
+            for (idx, reg) in regs.iter_mut().enumerate() {
+                if changed & (1 << idx) != 0 {
+                    *reg = read_u64(r)?;
+                }
+            }
+            records.push(TraceRecord { seq, changed, regs });
+        }
+        Ok(records)
+    }
+
+    /// Write one line per instruction with the registers it changed.
+    pub fn write_text<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.dropped() > 0 {
+            writeln!(w, "# {} earlier instructions dropped", self.dropped())?;
+        }
+        for record in &self.records {
+            write!(w, "{:>10} {:#018x}", record.seq, record.rip())?;
+            for (reg, value) in record.deltas() {
+                write!(w, " {}={:#x}", reg, value)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+/// Single-steps the guest from `start_rip`, recording every instruction.
+///
+/// Each hit sets RFLAGS.TF before resuming, so the next instruction traps
+/// back into the same handler.
+pub struct Tracer {
+    ring: Arc<Mutex<TraceRing>>,
+    tracepoint: Tracepoint,
+}
+
+impl Tracer {
+    pub fn new(start_rip: u64, capacity: usize) -> Self {
+        let ring = Arc::new(Mutex::new(TraceRing::new(capacity)));
+        let shared = ring.clone();
+        let tracepoint = Tracepoint::at(start_rip, move |regs| {
+            if let Ok(mut ring) = shared.lock() {
+                ring.record(regs);
+            }
+            regs.set_rflags(regs.rflags() | RFLAGS_TF);
+        });
+        Self { ring, tracepoint }
+    }
+
+    pub fn enable<D: Device>(&mut self, device: &D) -> Result<TracepointGuard<'_>> {
+        self.tracepoint.enable(device)
+    }
+
+    pub fn ring(&self) -> MutexGuard<'_, TraceRing> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(rip: u64, rax: u64) -> DuneTrapRegs {
+        let mut regs = DuneTrapRegs::default();
+        regs.set_rip(rip).set_rax(rax).set_rsp(0x7000);
+        regs
+    }
+
+    #[test]
+    fn ring_records_deltas_and_wraps() {
+        let mut ring = TraceRing::new(2);
+        ring.record(&step(0x1000, 1));
+        ring.record(&step(0x1003, 1));
+        ring.record(&step(0x1006, 2));
+
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.dropped(), 1);
+        let records: Vec<_> = ring.iter().collect();
+        assert_eq!(records[0].changed(), 0);
+        assert_eq!(records[1].deltas().collect::<Vec<_>>(), vec![(Register::Rax, 2)]);
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let mut ring = TraceRing::new(8);
+        ring.record(&step(0x1000, 1));
+        ring.record(&step(0x1004, 5));
+
+        let mut buf = Vec::new();
+        ring.write_binary(&mut buf).unwrap();
+        let decoded = TraceRing::read_binary(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded, ring.iter().copied().collect::<Vec<_>>());
+
+        let mut text = Vec::new();
+        ring.write_text(&mut text).unwrap();
+        assert!(String::from_utf8(text).unwrap().contains("0x0000000000001004 rax=0x5"));
+    }
+
+    #[test]
+    fn binary_roundtrip_after_wrap() {
+        let mut ring = TraceRing::new(2);
+        ring.record(&step(0x1000, 1));
+        ring.record(&step(0x1003, 1));
+        ring.record(&step(0x1006, 2));
+
+        let mut buf = Vec::new();
+        ring.write_binary(&mut buf).unwrap();
+        let decoded = TraceRing::read_binary(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for (decoded, record) in decoded.iter().zip(ring.iter()) {
+            assert_eq!(decoded.seq(), record.seq());
+            assert_eq!(decoded.registers(), record.registers());
+        }
+        assert_eq!(decoded[0].registers()[Register::Rsp], 0x7000);
+        assert_eq!(decoded[0].registers()[Register::Rax], 1);
+
+        ring.clear();
+        assert_eq!((ring.total(), ring.dropped()), (0, 0));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::funcs;
+use crate::{funcs, offset_consts};
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone, Default)]
@@ -60,3 +60,7 @@ impl DuneTf {
     funcs!(ss, u16);
 }
 
+offset_consts!(DuneTf, DUNE_TF {
+    rdi, rsi, rdx, rcx, r8, r9, r10, r11, rbx, rbp, r12, r13, r14, r15, rax,
+    err, rip, cs, rflags, rsp, ss,
+});
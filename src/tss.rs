@@ -1,6 +1,5 @@
-use std::mem::offset_of;
-
-use crate::{funcs, funcs_vec};
+use crate::abi::abi_layout;
+use crate::{funcs, funcs_vec, offset_consts};
 
 
 #[repr(C, packed)]
@@ -21,18 +20,40 @@ pub struct Tss {
     reserved0: u32,
     pub tss_rsp: [u64; 3], // Stack pointer for CPL 0, 1, 2
     reserved1: u64,
-    tss_ist: [u64; 7], // Note: tss_ist[0] is ignored
+    tss_ist: [u64; 7], // IST1-IST7: IST n lives in tss_ist[n - 1]
     reserved2: u64,
     reserved3: u16,
     tss_iomb: u16, // I/O map base
     tss_iopb: [u8; 0],
 }
 
-pub const TSS_IOPB : usize = offset_of!(Tss, tss_iopb);
+// The reserved words are named differently in libdune, so only the fields
+// both sides use are compared.
+abi_layout!(Tss => Tss { tss_rsp, tss_ist, tss_iomb, tss_iopb });
+
+offset_consts!(Tss {
+    TSS_RSP = tss_rsp,
+    TSS_IST = tss_ist,
+    TSS_IOMB = tss_iomb,
+    TSS_IOPB = tss_iopb,
+});
 
 impl Tss {
 
     funcs!(tss_iomb, u16);
     funcs_vec!(tss_rsp, u64);
     funcs_vec!(tss_ist, u64);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 64-bit TSS format from the Intel SDM, Vol. 3A, "Task Management in
+    /// 64-bit Mode".
+    #[test]
+    fn hardware_layout() {
+        assert_eq!(std::mem::size_of::<Tss>(), 104);
+        assert_eq!((TSS_RSP, TSS_IST, TSS_IOMB, TSS_IOPB), (0x4, 0x24, 0x66, 0x68));
+    }
+}
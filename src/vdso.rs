@@ -0,0 +1,337 @@
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use x86_64::VirtAddr;
+
+use crate::backend::{Backend, PAGE_PERM_READ, PAGE_SIZE};
+use crate::{Error, Result};
+
+pub const VDSO_MAGIC: u64 = u64::from_le_bytes(*b"DUNEVDSO");
+pub const VDSO_VERSION: u32 = 1;
+
+/// Values published on the shared page.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct VdsoSnapshot {
+    pub realtime_sec: u64,
+    pub realtime_nsec: u64,
+    pub monotonic_sec: u64,
+    pub monotonic_nsec: u64,
+    pub pid: u32,
+    pub ppid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// How many times the host has published.
+    pub updates: u64,
+}
+
+impl VdsoSnapshot {
+    /// Read the current host values.
+    pub fn now() -> Self {
+        let realtime = clock(libc::CLOCK_REALTIME);
+        let monotonic = clock(libc::CLOCK_MONOTONIC);
+        unsafe {
+            Self {
+                realtime_sec: realtime.tv_sec as u64,
+                realtime_nsec: realtime.tv_nsec as u64,
+                monotonic_sec: monotonic.tv_sec as u64,
+                monotonic_nsec: monotonic.tv_nsec as u64,
+                pid: libc::getpid() as u32,
+                ppid: libc::getppid() as u32,
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+                updates: 0,
+            }
+        }
+    }
+}
+
+fn clock(id: libc::clockid_t) -> libc::timespec {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(id, &mut ts) };
+    ts
+}
+
+/// Layout of the shared page, guarded by a sequence lock.
+///
+/// The host bumps `seq` to odd, stores the values and bumps it back to even.
+/// Readers retry while `seq` is odd or changed under them, so they never see
+/// a torn update and never take an exit.
+#[repr(C, align(64))]
+#[derive(Debug, Default)]
+pub struct VdsoData {
+    magic: AtomicU64,
+    version: AtomicU32,
+    seq: AtomicU32,
+    realtime_sec: AtomicU64,
+    realtime_nsec: AtomicU64,
+    monotonic_sec: AtomicU64,
+    monotonic_nsec: AtomicU64,
+    pid: AtomicU32,
+    ppid: AtomicU32,
+    uid: AtomicU32,
+    gid: AtomicU32,
+    updates: AtomicU64,
+}
+
+const _: () = assert!(std::mem::size_of::<VdsoData>() as u64 <= PAGE_SIZE);
+
+impl VdsoData {
+    pub fn is_valid(&self) -> bool {
+        self.magic.load(Ordering::Acquire) == VDSO_MAGIC
+            && self.version.load(Ordering::Relaxed) == VDSO_VERSION
+    }
+
+    /// Host side: publish new values.
+    pub fn publish(&self, values: &VdsoSnapshot) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.realtime_sec.store(values.realtime_sec, Ordering::Relaxed);
+        self.realtime_nsec.store(values.realtime_nsec, Ordering::Relaxed);
+        self.monotonic_sec.store(values.monotonic_sec, Ordering::Relaxed);
+        self.monotonic_nsec.store(values.monotonic_nsec, Ordering::Relaxed);
+        self.pid.store(values.pid, Ordering::Relaxed);
+        self.ppid.store(values.ppid, Ordering::Relaxed);
+        self.uid.store(values.uid, Ordering::Relaxed);
+        self.gid.store(values.gid, Ordering::Relaxed);
+        self.updates.fetch_add(1, Ordering::Relaxed);
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        if !self.is_valid() {
+            self.version.store(VDSO_VERSION, Ordering::Relaxed);
+            self.magic.store(VDSO_MAGIC, Ordering::Release);
+        }
+    }
+
+    /// Guest side: a consistent copy of the published values.
+    pub fn read(&self) -> VdsoSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let values = VdsoSnapshot {
+                realtime_sec: self.realtime_sec.load(Ordering::Relaxed),
+                realtime_nsec: self.realtime_nsec.load(Ordering::Relaxed),
+                monotonic_sec: self.monotonic_sec.load(Ordering::Relaxed),
+                monotonic_nsec: self.monotonic_nsec.load(Ordering::Relaxed),
+                pid: self.pid.load(Ordering::Relaxed),
+                ppid: self.ppid.load(Ordering::Relaxed),
+                uid: self.uid.load(Ordering::Relaxed),
+                gid: self.gid.load(Ordering::Relaxed),
+                updates: self.updates.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return values;
+            }
+        }
+    }
+}
+
+/// Guest-side accessors over the shared page.
+///
+/// Times are as fresh as the last host update, like the `*_COARSE` clocks.
+#[derive(Debug, Copy, Clone)]
+pub struct VdsoReader<'a> {
+    data: &'a VdsoData,
+}
+
+impl<'a> VdsoReader<'a> {
+    pub fn new(data: &'a VdsoData) -> Option<Self> {
+        data.is_valid().then_some(Self { data })
+    }
+
+    /// # Safety
+    ///
+    /// `addr` must be the guest address of a mapped shared page that stays
+    /// mapped for `'a`.
+    pub unsafe fn from_addr(addr: u64) -> Option<Self> {
+        Self::new(&*(addr as *const VdsoData))
+    }
+
+    pub fn clock_gettime(&self, clock: libc::clockid_t) -> Option<libc::timespec> {
+        let values = self.data.read();
+        let (sec, nsec) = match clock {
+            libc::CLOCK_REALTIME | libc::CLOCK_REALTIME_COARSE => {
+                (values.realtime_sec, values.realtime_nsec)
+            }
+            libc::CLOCK_MONOTONIC | libc::CLOCK_MONOTONIC_COARSE => {
+                (values.monotonic_sec, values.monotonic_nsec)
+            }
+            _ => return None,
+        };
+        Some(libc::timespec {
+            tv_sec: sec as libc::time_t,
+            tv_nsec: nsec as libc::c_long,
+        })
+    }
+
+    pub fn time(&self) -> u64 {
+        self.data.read().realtime_sec
+    }
+
+    pub fn getpid(&self) -> u32 {
+        self.data.read().pid
+    }
+
+    pub fn getppid(&self) -> u32 {
+        self.data.read().ppid
+    }
+
+    pub fn getuid(&self) -> u32 {
+        self.data.read().uid
+    }
+
+    pub fn getgid(&self) -> u32 {
+        self.data.read().gid
+    }
+}
+
+/// One page of memory shared between host and guest.
+///
+/// It is backed by a memfd mapped twice: writable for the host updater and
+/// read-only at the address handed to the guest, so the guest cannot forge
+/// values even under Dune, where guest and host share page tables.
+#[derive(Debug)]
+pub struct VdsoPage {
+    host: u64,
+    guest: u64,
+}
+
+fn map_shared(fd: libc::c_int, prot: libc::c_int) -> Result<u64> {
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            PAGE_SIZE as usize,
+            prot,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::last_syscall("mmap"));
+    }
+    Ok(addr as u64)
+}
+
+impl VdsoPage {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe { libc::memfd_create(c"dune-vdso".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_syscall("memfd_create"));
+        }
+        let page = (|| {
+            if unsafe { libc::ftruncate(fd, PAGE_SIZE as libc::off_t) } < 0 {
+                return Err(Error::last_syscall("ftruncate"));
+            }
+            let host = map_shared(fd, libc::PROT_READ | libc::PROT_WRITE)?;
+            let guest = match map_shared(fd, libc::PROT_READ) {
+                Ok(guest) => guest,
+                Err(err) => {
+                    unsafe { libc::munmap(host as *mut _, PAGE_SIZE as usize) };
+                    return Err(err);
+                }
+            };
+            Ok(Self { host, guest })
+        })();
+        /* the mappings keep the memory alive */
+        unsafe { libc::close(fd) };
+
+        let page = page?;
+        page.update();
+        Ok(page)
+    }
+
+    pub fn data(&self) -> &VdsoData {
+        unsafe { &*(self.host as *const VdsoData) }
+    }
+
+    /// Address the guest reads the page at.
+    pub fn guest_addr(&self) -> u64 {
+        self.guest
+    }
+
+    /// Make the guest mapping readable, and nothing else, through the backend.
+    pub fn install(&self, backend: &dyn Backend) -> Result<()> {
+        backend.set_page_perm(VirtAddr::new(self.guest), 1, PAGE_PERM_READ)
+    }
+
+    /// Publish the current time and ids.
+    pub fn update(&self) {
+        self.data().publish(&VdsoSnapshot::now());
+    }
+
+    /// Refresh the page every `interval` on a background thread.
+    pub fn spawn_updater(self: &Arc<Self>, interval: Duration) -> VdsoUpdater {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let page = self.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    page.update();
+                    std::thread::sleep(interval);
+                }
+            })
+        };
+        VdsoUpdater {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for VdsoPage {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.guest as *mut _, PAGE_SIZE as usize);
+            libc::munmap(self.host as *mut _, PAGE_SIZE as usize);
+        }
+    }
+}
+
+/// Stops the background updater when dropped.
+pub struct VdsoUpdater {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for VdsoUpdater {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+
+    #[test]
+    fn guest_reads_host_updates() {
+        let page = VdsoPage::new().unwrap();
+        let backend = MockBackend::new();
+        page.install(&backend).unwrap();
+        assert_eq!(backend.page_perms()[0].va, page.guest_addr());
+
+        let reader = unsafe { VdsoReader::from_addr(page.guest_addr()) }.unwrap();
+        assert_eq!(reader.getpid(), std::process::id());
+
+        let mut values = VdsoSnapshot::now();
+        values.monotonic_sec = 42;
+        page.data().publish(&values);
+        let ts = reader.clock_gettime(libc::CLOCK_MONOTONIC).unwrap();
+        assert_eq!(ts.tv_sec, 42);
+        assert!(reader.clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID).is_none());
+        assert_eq!(page.data().read().updates, 2);
+    }
+}
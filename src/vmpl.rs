@@ -1,4 +1,5 @@
-use crate::funcs;
+use crate::abi::abi_layout;
+use crate::{funcs, offset_consts};
 use x86_64::{PhysAddr, VirtAddr};
 
 #[allow(dead_code)]
@@ -13,6 +14,8 @@ pub struct VmsaSeg {
     base: u64,
 }
 
+abi_layout!(VmsaSeg => vmsa_seg { selector, attrib, limit, base });
+
 impl VmsaSeg {
     // Define the methods of VmsaSeg here
     // For example:
@@ -31,6 +34,8 @@ impl VmsaSeg {
     funcs!(base, u64);
 }
 
+offset_consts!(VmsaSeg, VMSA_SEG { selector, attrib, limit, base });
+
 #[allow(dead_code)]
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone, Default)]
@@ -43,6 +48,8 @@ pub struct VcpuConfig {
     lstar: u64,
 }
 
+abi_layout!(VcpuConfig => vcpu_config { fs, gs, gdtr, idtr, tr, lstar });
+
 impl VcpuConfig {
 
     funcs!(fs, VmsaSeg);
@@ -53,6 +60,8 @@ impl VcpuConfig {
     funcs!(lstar, u64);
 }
 
+offset_consts!(VcpuConfig, VCPU_CONFIG { fs, gs, gdtr, idtr, tr, lstar });
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct GetPages {
@@ -61,6 +70,8 @@ pub struct GetPages {
     phys: u64,
 }
 
+abi_layout!(GetPages => get_pages_t { num_pages, mapping, phys });
+
 impl GetPages {
 
     funcs!(num_pages, u64);
@@ -72,6 +83,8 @@ impl GetPages {
     }
 }
 
+offset_consts!(GetPages, GET_PAGES { num_pages, mapping, phys });
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct VmplArgs {
@@ -81,6 +94,19 @@ pub struct VmplArgs {
     nr_pages: u32,
 }
 
+abi_layout!(VmplArgs => vmpl_args { gva, page_size, attrs, nr_pages });
+
+impl VmplArgs {
+    pub fn new(gva: u64, page_size: u32, attrs: u32, nr_pages: u32) -> Self {
+        Self { gva, page_size, attrs, nr_pages }
+    }
+
+    funcs!(gva, u64);
+    funcs!(page_size, u32);
+    funcs!(attrs, u32);
+    funcs!(nr_pages, u32);
+}
+
 #[allow(dead_code)]
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -91,6 +117,8 @@ pub struct VmplLayout {
     mmap_end: VirtAddr,
 }
 
+abi_layout!(VmplLayout => vmpl_layout { phys_base, phys_end, mmap_base, mmap_end });
+
 impl VmplLayout {
     pub fn new() -> Self {
         Self {
@@ -126,6 +154,8 @@ pub struct VmplSeimi {
     pub pgd_super: u64,
 }
 
+abi_layout!(VmplSeimi => vmpl_seimi_t { pgd_user, pgd_super });
+
 impl VmplSeimi {
     pub fn new(pgd_user: u64, pgd_super: u64) -> Self {
         Self { pgd_user, pgd_super }
@@ -0,0 +1,303 @@
+use std::mem::{offset_of, size_of};
+
+use crate::funcs;
+use crate::funcs_ref;
+use crate::vmpl::{VcpuConfig, VmsaSeg};
+
+pub const VMSA_SIZE: usize = 4096;
+
+/// SEV-ES/SNP VM save area, as laid out by `struct sev_es_save_area`.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Vmsa {
+    es: VmsaSeg,
+    cs: VmsaSeg,
+    ss: VmsaSeg,
+    ds: VmsaSeg,
+    fs: VmsaSeg,
+    gs: VmsaSeg,
+    gdtr: VmsaSeg,
+    ldtr: VmsaSeg,
+    idtr: VmsaSeg,
+    tr: VmsaSeg,
+    vmpl0_ssp: u64,
+    vmpl1_ssp: u64,
+    vmpl2_ssp: u64,
+    vmpl3_ssp: u64,
+    u_cet: u64,
+    reserved_0xc8: [u8; 2],
+    vmpl: u8,
+    cpl: u8,
+    reserved_0xcc: [u8; 4],
+    efer: u64,
+    reserved_0xd8: [u8; 104],
+    xss: u64,
+    cr4: u64,
+    cr3: u64,
+    cr0: u64,
+    dr7: u64,
+    dr6: u64,
+    rflags: u64,
+    rip: u64,
+    dr0: u64,
+    dr1: u64,
+    dr2: u64,
+    dr3: u64,
+    dr0_addr_mask: u64,
+    dr1_addr_mask: u64,
+    dr2_addr_mask: u64,
+    dr3_addr_mask: u64,
+    reserved_0x1c0: [u8; 24],
+    rsp: u64,
+    s_cet: u64,
+    ssp: u64,
+    isst_addr: u64,
+    rax: u64,
+    star: u64,
+    lstar: u64,
+    cstar: u64,
+    sfmask: u64,
+    kernel_gs_base: u64,
+    sysenter_cs: u64,
+    sysenter_esp: u64,
+    sysenter_eip: u64,
+    cr2: u64,
+    reserved_0x248: [u8; 32],
+    g_pat: u64,
+    dbgctl: u64,
+    br_from: u64,
+    br_to: u64,
+    last_excp_from: u64,
+    last_excp_to: u64,
+    reserved_0x298: [u8; 80],
+    pkru: u32,
+    tsc_aux: u32,
+    reserved_0x2f0: [u8; 24],
+    rcx: u64,
+    rdx: u64,
+    rbx: u64,
+    reserved_0x320: u64, /* rsp already available at 0x01d8 */
+    rbp: u64,
+    rsi: u64,
+    rdi: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    reserved_0x380: [u8; 16],
+    guest_exit_info_1: u64,
+    guest_exit_info_2: u64,
+    guest_exit_int_info: u64,
+    guest_nrip: u64,
+    sev_features: u64,
+    vintr_ctrl: u64,
+    guest_exit_code: u64,
+    virtual_tom: u64,
+    tlb_id: u64,
+    pcpu_id: u64,
+    event_inj: u64,
+    xcr0: u64,
+    reserved_0x3f0: [u8; 16],
+
+    /* floating point area */
+    x87_dp: u64,
+    mxcsr: u32,
+    x87_ftw: u16,
+    x87_fsw: u16,
+    x87_fcw: u16,
+    x87_fop: u16,
+    x87_ds: u16,
+    x87_cs: u16,
+    x87_rip: u64,
+    fpreg_x87: [u8; 80],
+    fpreg_xmm: [u8; 256],
+    fpreg_ymm: [u8; 256],
+    manual_padding: [u8; 2448],
+}
+
+impl Vmsa {
+    pub fn new() -> Self {
+        // SAFETY: every field is a plain integer or byte array.
+        unsafe { std::mem::zeroed() }
+    }
+
+    funcs!(es, VmsaSeg);
+    funcs!(cs, VmsaSeg);
+    funcs!(ss, VmsaSeg);
+    funcs!(ds, VmsaSeg);
+    funcs!(fs, VmsaSeg);
+    funcs!(gs, VmsaSeg);
+    funcs!(gdtr, VmsaSeg);
+    funcs!(ldtr, VmsaSeg);
+    funcs!(idtr, VmsaSeg);
+    funcs!(tr, VmsaSeg);
+    funcs!(vmpl0_ssp, u64);
+    funcs!(vmpl1_ssp, u64);
+    funcs!(vmpl2_ssp, u64);
+    funcs!(vmpl3_ssp, u64);
+    funcs!(u_cet, u64);
+    funcs!(vmpl, u8);
+    funcs!(cpl, u8);
+    funcs!(efer, u64);
+    funcs!(xss, u64);
+    funcs!(cr4, u64);
+    funcs!(cr3, u64);
+    funcs!(cr0, u64);
+    funcs!(dr7, u64);
+    funcs!(dr6, u64);
+    funcs!(rflags, u64);
+    funcs!(rip, u64);
+    funcs!(dr0, u64);
+    funcs!(dr1, u64);
+    funcs!(dr2, u64);
+    funcs!(dr3, u64);
+    funcs!(dr0_addr_mask, u64);
+    funcs!(dr1_addr_mask, u64);
+    funcs!(dr2_addr_mask, u64);
+    funcs!(dr3_addr_mask, u64);
+    funcs!(rsp, u64);
+    funcs!(s_cet, u64);
+    funcs!(ssp, u64);
+    funcs!(isst_addr, u64);
+    funcs!(rax, u64);
+    funcs!(star, u64);
+    funcs!(lstar, u64);
+    funcs!(cstar, u64);
+    funcs!(sfmask, u64);
+    funcs!(kernel_gs_base, u64);
+    funcs!(sysenter_cs, u64);
+    funcs!(sysenter_esp, u64);
+    funcs!(sysenter_eip, u64);
+    funcs!(cr2, u64);
+    funcs!(g_pat, u64);
+    funcs!(dbgctl, u64);
+    funcs!(br_from, u64);
+    funcs!(br_to, u64);
+    funcs!(last_excp_from, u64);
+    funcs!(last_excp_to, u64);
+    funcs!(pkru, u32);
+    funcs!(tsc_aux, u32);
+    funcs!(rcx, u64);
+    funcs!(rdx, u64);
+    funcs!(rbx, u64);
+    funcs!(rbp, u64);
+    funcs!(rsi, u64);
+    funcs!(rdi, u64);
+    funcs!(r8, u64);
+    funcs!(r9, u64);
+    funcs!(r10, u64);
+    funcs!(r11, u64);
+    funcs!(r12, u64);
+    funcs!(r13, u64);
+    funcs!(r14, u64);
+    funcs!(r15, u64);
+    funcs!(guest_exit_info_1, u64);
+    funcs!(guest_exit_info_2, u64);
+    funcs!(guest_exit_int_info, u64);
+    funcs!(guest_nrip, u64);
+    funcs!(sev_features, u64);
+    funcs!(vintr_ctrl, u64);
+    funcs!(guest_exit_code, u64);
+    funcs!(virtual_tom, u64);
+    funcs!(tlb_id, u64);
+    funcs!(pcpu_id, u64);
+    funcs!(event_inj, u64);
+    funcs!(xcr0, u64);
+    funcs!(x87_dp, u64);
+    funcs!(mxcsr, u32);
+    funcs!(x87_ftw, u16);
+    funcs!(x87_fsw, u16);
+    funcs!(x87_fcw, u16);
+    funcs!(x87_fop, u16);
+    funcs!(x87_ds, u16);
+    funcs!(x87_cs, u16);
+    funcs!(x87_rip, u64);
+    funcs_ref!(fpreg_x87, [u8; 80]);
+    funcs_ref!(fpreg_xmm, [u8; 256]);
+    funcs_ref!(fpreg_ymm, [u8; 256]);
+
+    /// Copy the fields carried by `VcpuConfig` into this save area.
+    pub fn apply_config(&mut self, config: &VcpuConfig) -> &mut Self {
+        self.fs = config.fs();
+        self.gs = config.gs();
+        self.gdtr = config.gdtr();
+        self.idtr = config.idtr();
+        self.tr = config.tr();
+        self.lstar = config.lstar();
+        self
+    }
+}
+
+impl Default for Vmsa {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&VcpuConfig> for Vmsa {
+    fn from(config: &VcpuConfig) -> Self {
+        let mut vmsa = Vmsa::new();
+        vmsa.apply_config(config);
+        vmsa
+    }
+}
+
+impl From<&Vmsa> for VcpuConfig {
+    fn from(vmsa: &Vmsa) -> Self {
+        let mut config = VcpuConfig::default();
+        config
+            .set_fs(vmsa.fs())
+            .set_gs(vmsa.gs())
+            .set_gdtr(vmsa.gdtr())
+            .set_idtr(vmsa.idtr())
+            .set_tr(vmsa.tr())
+            .set_lstar(vmsa.lstar());
+        config
+    }
+}
+
+const _: () = assert!(size_of::<VmsaSeg>() == 0x10);
+const _: () = assert!(size_of::<Vmsa>() == VMSA_SIZE);
+const _: () = assert!(offset_of!(Vmsa, es) == 0x000);
+const _: () = assert!(offset_of!(Vmsa, fs) == 0x040);
+const _: () = assert!(offset_of!(Vmsa, gdtr) == 0x060);
+const _: () = assert!(offset_of!(Vmsa, idtr) == 0x080);
+const _: () = assert!(offset_of!(Vmsa, tr) == 0x090);
+const _: () = assert!(offset_of!(Vmsa, vmpl0_ssp) == 0x0a0);
+const _: () = assert!(offset_of!(Vmsa, vmpl) == 0x0ca);
+const _: () = assert!(offset_of!(Vmsa, cpl) == 0x0cb);
+const _: () = assert!(offset_of!(Vmsa, efer) == 0x0d0);
+const _: () = assert!(offset_of!(Vmsa, xss) == 0x140);
+const _: () = assert!(offset_of!(Vmsa, cr4) == 0x148);
+const _: () = assert!(offset_of!(Vmsa, cr3) == 0x150);
+const _: () = assert!(offset_of!(Vmsa, cr0) == 0x158);
+const _: () = assert!(offset_of!(Vmsa, rflags) == 0x170);
+const _: () = assert!(offset_of!(Vmsa, rip) == 0x178);
+const _: () = assert!(offset_of!(Vmsa, dr0_addr_mask) == 0x1a0);
+const _: () = assert!(offset_of!(Vmsa, rsp) == 0x1d8);
+const _: () = assert!(offset_of!(Vmsa, rax) == 0x1f8);
+const _: () = assert!(offset_of!(Vmsa, star) == 0x200);
+const _: () = assert!(offset_of!(Vmsa, lstar) == 0x208);
+const _: () = assert!(offset_of!(Vmsa, kernel_gs_base) == 0x220);
+const _: () = assert!(offset_of!(Vmsa, cr2) == 0x240);
+const _: () = assert!(offset_of!(Vmsa, g_pat) == 0x268);
+const _: () = assert!(offset_of!(Vmsa, last_excp_to) == 0x290);
+const _: () = assert!(offset_of!(Vmsa, pkru) == 0x2e8);
+const _: () = assert!(offset_of!(Vmsa, tsc_aux) == 0x2ec);
+const _: () = assert!(offset_of!(Vmsa, rcx) == 0x308);
+const _: () = assert!(offset_of!(Vmsa, rbp) == 0x328);
+const _: () = assert!(offset_of!(Vmsa, r8) == 0x340);
+const _: () = assert!(offset_of!(Vmsa, r15) == 0x378);
+const _: () = assert!(offset_of!(Vmsa, guest_exit_info_1) == 0x390);
+const _: () = assert!(offset_of!(Vmsa, sev_features) == 0x3b0);
+const _: () = assert!(offset_of!(Vmsa, xcr0) == 0x3e8);
+const _: () = assert!(offset_of!(Vmsa, x87_dp) == 0x400);
+const _: () = assert!(offset_of!(Vmsa, x87_rip) == 0x418);
+const _: () = assert!(offset_of!(Vmsa, fpreg_x87) == 0x420);
+const _: () = assert!(offset_of!(Vmsa, fpreg_xmm) == 0x470);
+const _: () = assert!(offset_of!(Vmsa, fpreg_ymm) == 0x570);
+const _: () = assert!(offset_of!(Vmsa, manual_padding) == 0x670);